@@ -0,0 +1,234 @@
+//! Derive macros for the `ffa` crate.
+//!
+//! Use through the `derive` feature of `ffa` rather than depending on this
+//! crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, Attribute, Data, DataEnum, DataStruct, DeriveInput, Error, Fields, Ident,
+    LitInt, LitStr, Result,
+};
+
+/// Derives `ffa::msg::Payload` for a struct or an enum.
+///
+/// Struct fields are encoded in declaration order. Enum variants must each
+/// carry an explicit `#[ffa(opcode = N)]` tag, which is encoded as a `u64`
+/// ahead of the variant's fields.
+///
+/// By default the encoded size is checked at compile time against the 14
+/// argument registers of FFA_MSG_SEND_DIRECT_REQ2, x4-x17. Adding
+/// `#[ffa(direct_req)]` to the type checks against the 5 registers of
+/// FFA_MSG_SEND_DIRECT_REQ, x3-x7, instead.
+#[proc_macro_derive(FfaPayload, attributes(ffa))]
+pub fn derive_ffa_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "FfaPayload cannot be derived for generic types",
+        ));
+    }
+
+    let name = &input.ident;
+    let limit = if type_attr_direct_req(&input.attrs)? {
+        quote!(::ffa::msg::FfaMsg::DIRECT_REQ_ARGS_LEN)
+    } else {
+        quote!(::ffa::msg::FfaMsg::ARGS_LEN)
+    };
+
+    let (size, encode, decode) = match &input.data {
+        Data::Struct(data) => expand_struct(data),
+        Data::Enum(data) => expand_enum(data)?,
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                name,
+                "FfaPayload cannot be derived for unions",
+            ))
+        }
+    };
+
+    let message = LitStr::new(
+        &format!("`{name}` does not fit in the direct message argument registers"),
+        Span::call_site(),
+    );
+
+    Ok(quote! {
+        impl ::ffa::msg::Payload for #name {
+            const SIZE: usize = #size;
+
+            fn encode(&self, __writer: &mut ::ffa::msg::PayloadWriter<'_>) -> ::ffa::Result<()> {
+                #encode
+            }
+
+            fn decode(__reader: &mut ::ffa::msg::PayloadReader<'_>) -> ::ffa::Result<Self> {
+                #decode
+            }
+        }
+
+        const _: () = ::core::assert!(
+            <#name as ::ffa::msg::Payload>::SIZE <= #limit,
+            #message
+        );
+    })
+}
+
+fn expand_struct(data: &DataStruct) -> (TokenStream2, TokenStream2, TokenStream2) {
+    let size = fields_size(&data.fields);
+    let bindings = field_bindings(&data.fields);
+    let pattern = fields_pattern(quote!(Self), &data.fields, &bindings);
+
+    let encode = quote! {
+        let #pattern = self;
+        #( __writer.write(#bindings)?; )*
+        Ok(())
+    };
+
+    let decode = quote! {
+        #( let #bindings = __reader.read()?; )*
+        Ok(#pattern)
+    };
+
+    (size, encode, decode)
+}
+
+fn expand_enum(data: &DataEnum) -> Result<(TokenStream2, TokenStream2, TokenStream2)> {
+    if data.variants.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "FfaPayload cannot be derived for enums without variants",
+        ));
+    }
+
+    let mut opcodes: Vec<(u64, &Ident)> = Vec::new();
+    let mut size = quote!(0usize);
+    let mut encode_arms = Vec::new();
+    let mut decode_arms = Vec::new();
+
+    for variant in &data.variants {
+        let ident = &variant.ident;
+        let opcode = variant_opcode(&variant.attrs, ident)?;
+
+        if let Some((_, other)) = opcodes.iter().find(|(op, _)| *op == opcode) {
+            return Err(Error::new_spanned(
+                ident,
+                format!("opcode {opcode} is already used by `{other}`"),
+            ));
+        }
+        opcodes.push((opcode, ident));
+
+        let variant_size = fields_size(&variant.fields);
+        size = quote! {{
+            let a = #size;
+            let b = #variant_size;
+            if a > b { a } else { b }
+        }};
+
+        let bindings = field_bindings(&variant.fields);
+        let pattern = fields_pattern(quote!(Self::#ident), &variant.fields, &bindings);
+
+        encode_arms.push(quote! {
+            #pattern => {
+                __writer.write(&#opcode)?;
+                #( __writer.write(#bindings)?; )*
+            }
+        });
+
+        decode_arms.push(quote! {
+            #opcode => {
+                #( let #bindings = __reader.read()?; )*
+                Ok(#pattern)
+            }
+        });
+    }
+
+    let size = quote!(<u64 as ::ffa::msg::Payload>::SIZE + #size);
+
+    let encode = quote! {
+        match self {
+            #( #encode_arms )*
+        }
+        Ok(())
+    };
+
+    let decode = quote! {
+        match __reader.read::<u64>()? {
+            #( #decode_arms )*
+            _ => Err(::ffa::FfaError::InvalidParameters),
+        }
+    };
+
+    Ok((size, encode, decode))
+}
+
+fn fields_size(fields: &Fields) -> TokenStream2 {
+    let types = fields.iter().map(|f| &f.ty);
+    quote!(0usize #( + <#types as ::ffa::msg::Payload>::SIZE )*)
+}
+
+/// Locals the fields are bound to, named so that they can't shadow the
+/// generated code's own.
+fn field_bindings(fields: &Fields) -> Vec<Ident> {
+    (0..fields.len())
+        .map(|i| format_ident!("__field_{}", i))
+        .collect()
+}
+
+fn fields_pattern(path: TokenStream2, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(_) => {
+            let names = fields.iter().map(|f| &f.ident);
+            quote!(#path { #( #names: #bindings ),* })
+        }
+        Fields::Unnamed(_) => quote!(#path ( #( #bindings ),* )),
+        Fields::Unit => quote!(#path),
+    }
+}
+
+fn type_attr_direct_req(attrs: &[Attribute]) -> Result<bool> {
+    let mut direct_req = false;
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("ffa")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("direct_req") {
+                direct_req = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported ffa attribute"))
+            }
+        })?;
+    }
+
+    Ok(direct_req)
+}
+
+fn variant_opcode(attrs: &[Attribute], ident: &Ident) -> Result<u64> {
+    let mut opcode = None;
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("ffa")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("opcode") {
+                let lit: LitInt = meta.value()?.parse()?;
+                opcode = Some(lit.base10_parse::<u64>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported ffa attribute"))
+            }
+        })?;
+    }
+
+    opcode.ok_or_else(|| {
+        Error::new_spanned(
+            ident,
+            "FfaPayload enum variants require an #[ffa(opcode = N)] attribute",
+        )
+    })
+}
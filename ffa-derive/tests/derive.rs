@@ -0,0 +1,174 @@
+use ffa::msg::{FfaMsg, FfaPayload, Payload, PayloadReader, PayloadWriter};
+use ffa::{FfaError, FfaFunctionId, FfaParams};
+
+#[derive(FfaPayload, Debug, PartialEq)]
+struct Named {
+    a: u8,
+    b: u32,
+    c: bool,
+    d: [u16; 3],
+}
+
+#[derive(FfaPayload, Debug, PartialEq)]
+struct Tuple(u64, i8);
+
+#[derive(FfaPayload, Debug, PartialEq)]
+struct Unit;
+
+#[derive(FfaPayload, Debug, PartialEq)]
+enum Command {
+    #[ffa(opcode = 1)]
+    Reset,
+    #[ffa(opcode = 2)]
+    Write { addr: u64, value: u32 },
+    #[ffa(opcode = 0x10)]
+    Read(u64),
+}
+
+#[derive(FfaPayload, Debug, PartialEq)]
+#[ffa(direct_req)]
+struct Small {
+    a: u64,
+    b: u64,
+    c: u64,
+    d: u64,
+    e: u64,
+}
+
+// Fields named like the locals of the generated code
+#[derive(FfaPayload, Debug, PartialEq)]
+struct Shadowing {
+    w: u8,
+    r: u16,
+}
+
+#[derive(FfaPayload, Debug, PartialEq)]
+enum ShadowingCommand {
+    #[ffa(opcode = 1)]
+    Write { w: u64, r: u8 },
+}
+
+fn round_trip<T: Payload + PartialEq + core::fmt::Debug>(value: T) {
+    let mut buf = [0u8; FfaMsg::ARGS_LEN];
+    let mut w = PayloadWriter::new(&mut buf);
+    w.write(&value).unwrap();
+    let written = w.position();
+
+    let mut r = PayloadReader::new(&buf);
+    assert_eq!(r.read::<T>().unwrap(), value);
+    assert_eq!(r.position(), written);
+}
+
+#[test]
+fn structs_round_trip() {
+    round_trip(Named {
+        a: 1,
+        b: 0xdead_beef,
+        c: true,
+        d: [1, 2, 3],
+    });
+    round_trip(Tuple(u64::MAX, -1));
+    round_trip(Unit);
+    round_trip(Shadowing { w: 1, r: 2 });
+    round_trip(ShadowingCommand::Write { w: 4, r: 5 });
+
+    assert_eq!(Named::SIZE, 1 + 4 + 1 + 6);
+    assert_eq!(Tuple::SIZE, 9);
+    assert_eq!(Unit::SIZE, 0);
+}
+
+#[test]
+fn fields_are_encoded_in_order() {
+    let mut buf = [0u8; 9];
+    PayloadWriter::new(&mut buf)
+        .write(&Tuple(0x0102_0304_0506_0708, -2))
+        .unwrap();
+    assert_eq!(buf, [8, 7, 6, 5, 4, 3, 2, 1, 0xfe]);
+}
+
+#[test]
+fn enums_round_trip() {
+    round_trip(Command::Reset);
+    round_trip(Command::Write {
+        addr: 0x1000,
+        value: 7,
+    });
+    round_trip(Command::Read(0x2000));
+
+    // Opcode plus the largest variant
+    assert_eq!(Command::SIZE, 8 + 12);
+}
+
+#[test]
+fn enums_reject_unknown_opcodes() {
+    let mut buf = [0u8; 8];
+    PayloadWriter::new(&mut buf).write(&3u64).unwrap();
+    assert_eq!(
+        PayloadReader::new(&buf).read::<Command>(),
+        Err(FfaError::InvalidParameters)
+    );
+}
+
+#[test]
+fn direct_req2_payload_starts_at_x4() {
+    let mut msg = FfaMsg {
+        function_id: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+        ..Default::default()
+    };
+    msg.write_args(&Command::Read(0xabcd)).unwrap();
+
+    let params = FfaParams::from(&msg);
+    assert_eq!((params.x4, params.x5), (0x10, 0xabcd));
+
+    let msg = FfaMsg::from(params);
+    assert_eq!(msg.read_args::<Command>().unwrap(), Command::Read(0xabcd));
+}
+
+#[test]
+fn direct_req_payload_is_packed_in_x3_to_x7() {
+    let small = Small {
+        a: 1,
+        b: 2,
+        c: 3,
+        d: 4,
+        e: 5,
+    };
+    let mut msg = FfaMsg {
+        function_id: FfaFunctionId::FfaMsgSendDirectReq.into(),
+        ..Default::default()
+    };
+    msg.write_args(&small).unwrap();
+
+    let params = FfaParams::from(&msg);
+    assert_eq!(
+        [params.x2, params.x3, params.x4, params.x5, params.x6, params.x7, params.x8],
+        [0, 1, 2, 3, 4, 5, 0]
+    );
+
+    let msg = FfaMsg::from(params);
+    assert_eq!(msg.read_args::<Small>().unwrap(), small);
+}
+
+#[test]
+fn direct_req_rejects_larger_payloads() {
+    let mut msg = FfaMsg {
+        function_id: FfaFunctionId::FfaMsgSendDirectReq.into(),
+        ..Default::default()
+    };
+    assert_eq!(
+        msg.write_args(&Named {
+            a: 0,
+            b: 0,
+            c: false,
+            d: [0; 3]
+        }),
+        Ok(())
+    );
+    assert_eq!(msg.write_args(&[0u64; 6]), Err(FfaError::InvalidParameters));
+}
+
+#[test]
+fn compile_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
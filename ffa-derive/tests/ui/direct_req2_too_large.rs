@@ -0,0 +1,9 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+struct TooLarge {
+    words: [u64; 14],
+    extra: u8,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+#[ffa(direct_req)]
+struct TooLarge {
+    words: [u64; 5],
+    extra: u8,
+}
+
+fn main() {}
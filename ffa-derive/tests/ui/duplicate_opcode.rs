@@ -0,0 +1,11 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+enum Command {
+    #[ffa(opcode = 1)]
+    Reset,
+    #[ffa(opcode = 1)]
+    Write(u32),
+}
+
+fn main() {}
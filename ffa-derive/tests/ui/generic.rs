@@ -0,0 +1,8 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+struct Wrapper<T> {
+    value: T,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+enum Command {
+    #[ffa(opcode = 1)]
+    Reset,
+    Write(u32),
+}
+
+fn main() {}
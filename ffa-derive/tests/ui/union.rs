@@ -0,0 +1,9 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+union Bits {
+    a: u32,
+    b: f32,
+}
+
+fn main() {}
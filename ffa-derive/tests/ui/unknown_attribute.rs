@@ -0,0 +1,9 @@
+use ffa::msg::FfaPayload;
+
+#[derive(FfaPayload)]
+#[ffa(packed)]
+struct Flags {
+    bits: u32,
+}
+
+fn main() {}
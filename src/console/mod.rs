@@ -1,62 +1,213 @@
 use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
-use super::{ffa_smc, FfaError, FfaFunctionId, FfaParams, Result};
+use super::features::FfaFeatures;
+use super::{ffa_result, Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+/// FFA_CONSOLE_LOG variant in use, probed on first use
+static VARIANT: AtomicU8 = AtomicU8::new(VARIANT_UNKNOWN);
+
+const VARIANT_UNKNOWN: u8 = 0;
+const VARIANT_LOG32: u8 = 1;
+const VARIANT_LOG64: u8 = 2;
+
+/// Calls that failed
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes lost to failed calls
+static DROPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Prints dropped because they were issued while printing
+static NESTED_PRINTS: AtomicUsize = AtomicUsize::new(0);
+/// Cores printing, by [`core_id`] plus one, with 0 for free slots. Guards
+/// against a panic raised while printing, whose handler prints in turn,
+/// recursing.
+static PRINTING: [AtomicU64; PRINT_SLOTS] = [FREE_SLOT; PRINT_SLOTS];
+
+const PRINT_SLOTS: usize = 16;
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_SLOT: AtomicU64 = AtomicU64::new(0);
+
+/// Times a call answered with `Retry` is reissued before giving up
+const MAX_RETRIES: u32 = 8;
+
+/// Register layout of an FFA_CONSOLE_LOG variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConsoleLog {
+    pub function_id: FfaFunctionId,
+    /// Characters packed in each of w2/x2 onwards
+    pub chars_per_register: usize,
+    /// Characters per call
+    pub max_chars: usize,
+}
+
+impl ConsoleLog {
+    /// FFA_CONSOLE_LOG_32: 4 characters in each of w2-w7.
+    pub const LOG32: ConsoleLog = ConsoleLog {
+        function_id: FfaFunctionId::FfaConsoleLog32,
+        chars_per_register: 4,
+        max_chars: 24,
+    };
+
+    /// FFA_CONSOLE_LOG_64: 8 characters in each of x2-x17.
+    pub const LOG64: ConsoleLog = ConsoleLog {
+        function_id: FfaFunctionId::FfaConsoleLog,
+        chars_per_register: 8,
+        max_chars: 128,
+    };
+
+    /// Picks the variant supported by the SPMC with FFA_FEATURES, preferring
+    /// FFA_CONSOLE_LOG_64.
+    ///
+    /// SPMCs that can't tell are assumed to support FFA_CONSOLE_LOG_64.
+    pub fn probe<C: Conduit>(conduit: &mut C) -> ConsoleLog {
+        for variant in [Self::LOG64, Self::LOG32] {
+            match FfaFeatures::new(variant.function_id.into(), 0).exec_with(conduit) {
+                // FFA_FEATURES reports no length for FFA_CONSOLE_LOG, each
+                // variant carries as many characters as its registers hold
+                Ok(_) => return variant,
+                Err(FfaError::NotSupported) => continue,
+                Err(_) => break,
+            }
+        }
+
+        Self::LOG64
+    }
+
+    fn params(&self, bytes: &[u8]) -> FfaParams {
+        let mut regs = [0u64; 16];
+
+        for (reg, chunk) in regs.iter_mut().zip(bytes.chunks(self.chars_per_register)) {
+            let mut buf = [0u8; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            *reg = u64::from_le_bytes(buf);
+        }
+
+        FfaParams {
+            x0: self.function_id.into(),
+            x1: bytes.len() as u64,
+            x2: regs[0],
+            x3: regs[1],
+            x4: regs[2],
+            x5: regs[3],
+            x6: regs[4],
+            x7: regs[5],
+            x8: regs[6],
+            x9: regs[7],
+            x10: regs[8],
+            x11: regs[9],
+            x12: regs[10],
+            x13: regs[11],
+            x14: regs[12],
+            x15: regs[13],
+            x16: regs[14],
+            x17: regs[15],
+        }
+    }
+}
+
+/// Console output lost so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleStats {
+    /// FFA_CONSOLE_LOG calls that failed
+    pub errors: usize,
+    /// Bytes that were not logged because of failed calls
+    pub dropped_bytes: usize,
+    /// `print!`s dropped because they were issued while printing
+    pub nested_prints: usize,
+}
+
+impl ConsoleStats {
+    pub fn get() -> Self {
+        Self {
+            errors: ERRORS.load(Ordering::Relaxed),
+            dropped_bytes: DROPPED_BYTES.load(Ordering::Relaxed),
+            nested_prints: NESTED_PRINTS.load(Ordering::Relaxed),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct FfaConsole;
 
 impl FfaConsole {
-    const FFA_MAX_CHAR_COUNT: usize = 128;
-
     pub fn new() -> Self {
         Self
     }
 
     pub(crate) fn exec(&self, bytes: &[u8]) -> Result<()> {
-        for chunk in bytes.chunks(Self::FFA_MAX_CHAR_COUNT) {
-            self.exec_inner(FfaFunctionId::FfaConsoleLog, chunk)?;
+        self.exec_with(&mut SmcConduit, bytes)
+    }
+
+    /// Logs `bytes` through `conduit`, in as many calls as needed.
+    ///
+    /// Stops at the first failed call, the bytes left are accounted for in
+    /// [`ConsoleStats`].
+    pub fn exec_with<C: Conduit>(&self, conduit: &mut C, bytes: &[u8]) -> Result<()> {
+        let log = Self::variant(conduit);
+        self.log_with(conduit, &log, bytes)
+    }
+
+    /// Logs `bytes` through `conduit` with the `log` variant, see
+    /// [`FfaConsole::exec_with`].
+    pub fn log_with<C: Conduit>(
+        &self,
+        conduit: &mut C,
+        log: &ConsoleLog,
+        bytes: &[u8],
+    ) -> Result<()> {
+        for (i, chunk) in bytes.chunks(log.max_chars).enumerate() {
+            if let Err(err) = self.exec_inner(conduit, log, chunk) {
+                ERRORS.fetch_add(1, Ordering::Relaxed);
+                DROPPED_BYTES.fetch_add(bytes.len() - i * log.max_chars, Ordering::Relaxed);
+                return Err(err);
+            }
         }
 
         Ok(())
     }
 
-    fn exec_inner(&self, id: FfaFunctionId, bytes: &[u8]) -> Result<()> {
-        let mut iter = bytes.chunks(8).map(|c| {
-            let mut buf = [0u8; 8];
-            let len = 8.min(c.len());
-            buf[..len].copy_from_slice(&c[..len]);
-            u64::from_le_bytes(buf)
-        });
+    /// The variant in use, probing it through `conduit` on first use.
+    pub fn variant<C: Conduit>(conduit: &mut C) -> ConsoleLog {
+        match VARIANT.load(Ordering::Acquire) {
+            VARIANT_LOG32 => ConsoleLog::LOG32,
+            VARIANT_LOG64 => ConsoleLog::LOG64,
+            _ => {
+                let log = ConsoleLog::probe(conduit);
+                let variant = match log.function_id {
+                    FfaFunctionId::FfaConsoleLog32 => VARIANT_LOG32,
+                    _ => VARIANT_LOG64,
+                };
+                VARIANT.store(variant, Ordering::Release);
+                log
+            }
+        }
+    }
 
-        let params = FfaParams {
-            x0: id.into(),
-            x1: bytes.len() as u64,
-            x2: iter.next().unwrap_or(0),
-            x3: iter.next().unwrap_or(0),
-            x4: iter.next().unwrap_or(0),
-            x5: iter.next().unwrap_or(0),
-            x6: iter.next().unwrap_or(0),
-            x7: iter.next().unwrap_or(0),
-            x8: iter.next().unwrap_or(0),
-            x9: iter.next().unwrap_or(0),
-            x10: iter.next().unwrap_or(0),
-            x11: iter.next().unwrap_or(0),
-            x12: iter.next().unwrap_or(0),
-            x13: iter.next().unwrap_or(0),
-            x14: iter.next().unwrap_or(0),
-            x15: iter.next().unwrap_or(0),
-            x16: iter.next().unwrap_or(0),
-            x17: iter.next().unwrap_or(0),
-        };
-
-        let result = ffa_smc(params);
-        let error = FfaError::from(result.x2 as i64);
-
-        match error {
-            FfaError::NotSupported | FfaError::InvalidParameters | FfaError::Retry => Err(error),
-            FfaError::Ok => Ok(()),
-            _ => unreachable!(),
+    /// Logs a chunk of at most `log.max_chars` bytes.
+    ///
+    /// `Retry` means the SPMC is busy logging for another endpoint, so the
+    /// call is reissued after a growing delay rather than cutting the line.
+    fn exec_inner<C: Conduit>(
+        &self,
+        conduit: &mut C,
+        log: &ConsoleLog,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let params = log.params(bytes);
+
+        for attempt in 0..MAX_RETRIES {
+            match ffa_result(conduit.call(params)) {
+                Err(FfaError::Retry) => backoff(attempt),
+                result => return result.map(|_| ()),
+            }
         }
+
+        Err(FfaError::Retry)
+    }
+}
+
+fn backoff(attempt: u32) {
+    for _ in 0..(1u32 << attempt) * 64 {
+        core::hint::spin_loop();
     }
 }
 
@@ -66,11 +217,92 @@ impl fmt::Write for FfaConsole {
     }
 }
 
+/// Writer of a single print. Once a call failed, the rest of the output is
+/// counted as dropped instead of being logged.
+struct Printer<'a, C: Conduit> {
+    conduit: &'a mut C,
+    log: ConsoleLog,
+    failed: bool,
+}
+
+impl<C: Conduit> fmt::Write for Printer<'_, C> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.failed {
+            DROPPED_BYTES.fetch_add(s.len(), Ordering::Relaxed);
+        } else if FfaConsole::new()
+            .log_with(self.conduit, &self.log, s.as_bytes())
+            .is_err()
+        {
+            // The failed call counted the rest of `s`
+            self.failed = true;
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints `args`, never failing: lost output is accounted for in
+/// [`ConsoleStats`] instead.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    let log = FfaConsole::variant(&mut SmcConduit);
+
+    print_with(&mut SmcConduit, log, core_id(), args);
+}
+
+/// Affinity fields of MPIDR_EL1, telling the calling core apart from the
+/// others whichever vCPU it runs, or `None` where there is no such register.
+fn core_id() -> Option<u64> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mpidr: u64;
+        // SAFETY: reading MPIDR_EL1 has no side effects
+        unsafe {
+            core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack));
+        }
+        // Aff3 and Aff2-Aff0
+        Some(mpidr & 0xff_00ff_ffff)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    None
+}
+
+/// Prints `args` unless `core` is already printing. Prints from an unknown
+/// core, or while all the guard slots are taken, aren't guarded.
+fn print_with<C: Conduit>(
+    conduit: &mut C,
+    log: ConsoleLog,
+    core: Option<u64>,
+    args: fmt::Arguments,
+) {
     use fmt::Write;
 
-    FfaConsole::new().write_fmt(args).unwrap();
+    let slot = match core.map(|core| core + 1) {
+        // Only `core` itself claims slots holding its id, so none can appear
+        // between the check and the claim
+        Some(key) if PRINTING.iter().any(|s| s.load(Ordering::Acquire) == key) => {
+            NESTED_PRINTS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Some(key) => PRINTING.iter().find(|s| {
+            s.compare_exchange(0, key, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        }),
+        None => None,
+    };
+
+    // Printer never fails, formatting only does if a Display impl does
+    let _ = Printer {
+        conduit,
+        log,
+        failed: false,
+    }
+    .write_fmt(args);
+
+    if let Some(slot) = slot {
+        slot.store(0, Ordering::Release);
+    }
 }
 
 /// Prints without a newline.
@@ -92,3 +324,232 @@ macro_rules! println {
         $crate::console::_print(format_args!("\n"))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_error, ffa_success, FakeConduit};
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Serializes the tests updating the global counters
+    fn stats_lock() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[test]
+    fn log64_chunks_and_packing() {
+        let bytes: Vec<u8> = (0..300u32).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut c = FakeConduit::<16>::from_responses(&[ffa_success(0); 3]);
+
+        FfaConsole::new()
+            .log_with(&mut c, &ConsoleLog::LOG64, &bytes)
+            .unwrap();
+
+        let calls = c.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls
+            .iter()
+            .all(|call| call.x0 == FfaFunctionId::FfaConsoleLog.into()));
+        assert_eq!(
+            calls.iter().map(|call| call.x1).collect::<Vec<_>>(),
+            [128, 128, 44]
+        );
+        assert_eq!(calls[0].x2, u64::from_le_bytes(*b"abcdefgh"));
+        assert_eq!(
+            calls[0].x17,
+            u64::from_le_bytes(bytes[120..128].try_into().unwrap())
+        );
+        // 44 = 5 full registers and 4 characters
+        assert_eq!(
+            calls[2].x7,
+            u64::from_le_bytes([bytes[296], bytes[297], bytes[298], bytes[299], 0, 0, 0, 0])
+        );
+        assert_eq!(calls[2].x8, 0);
+    }
+
+    #[test]
+    fn log32_chunks_and_packing() {
+        let mut c = FakeConduit::<16>::from_responses(&[ffa_success(0); 2]);
+
+        FfaConsole::new()
+            .log_with(&mut c, &ConsoleLog::LOG32, b"0123456789abcdefghijklmnopq")
+            .unwrap();
+
+        let calls = c.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].x0, FfaFunctionId::FfaConsoleLog32.into());
+        assert_eq!((calls[0].x1, calls[1].x1), (24, 3));
+        assert_eq!(calls[0].x2, u32::from_le_bytes(*b"0123") as u64);
+        assert_eq!(calls[0].x7, u32::from_le_bytes(*b"klmn") as u64);
+        assert_eq!(calls[0].x8, 0);
+        assert_eq!(calls[1].x2, u32::from_le_bytes(*b"opq\0") as u64);
+    }
+
+    #[test]
+    fn retry_is_reissued() {
+        let _lock = stats_lock();
+        let mut c = FakeConduit::<16>::from_responses(&[
+            ffa_error(FfaError::Retry),
+            ffa_error(FfaError::Retry),
+            ffa_success(0),
+        ]);
+
+        FfaConsole::new()
+            .log_with(&mut c, &ConsoleLog::LOG64, b"hello")
+            .unwrap();
+        assert_eq!(c.calls().len(), 3);
+        assert!(c.calls().iter().all(|call| *call == c.calls()[0]));
+
+        let mut c =
+            FakeConduit::<16>::from_responses(&[ffa_error(FfaError::Retry); MAX_RETRIES as usize]);
+        assert_eq!(
+            FfaConsole::new().log_with(&mut c, &ConsoleLog::LOG64, b"hello"),
+            Err(FfaError::Retry)
+        );
+        assert_eq!(c.calls().len(), MAX_RETRIES as usize);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let _lock = stats_lock();
+        let mut c = FakeConduit::<16>::from_responses(&[
+            ffa_error(FfaError::InvalidParameters),
+            ffa_success(0),
+        ]);
+
+        assert_eq!(
+            FfaConsole::new().log_with(&mut c, &ConsoleLog::LOG64, b"hello"),
+            Err(FfaError::InvalidParameters)
+        );
+        assert_eq!(c.calls().len(), 1);
+    }
+
+    #[test]
+    fn probe_prefers_log64() {
+        // w2 of the response is not a character count
+        let mut c = FakeConduit::<16>::from_responses(&[FfaParams {
+            x2: 16,
+            ..ffa_success(0)
+        }]);
+        assert_eq!(ConsoleLog::probe(&mut c), ConsoleLog::LOG64);
+        assert_eq!(c.calls().len(), 1);
+        assert_eq!(c.calls()[0].x0, FfaFunctionId::FfaFeatures.into());
+        assert_eq!(c.calls()[0].x1, FfaFunctionId::FfaConsoleLog.into());
+    }
+
+    #[test]
+    fn probe_falls_back_to_log32() {
+        let mut c = FakeConduit::<16>::from_responses(&[
+            ffa_error(FfaError::NotSupported),
+            FfaParams {
+                x2: 16,
+                ..ffa_success(0)
+            },
+        ]);
+        assert_eq!(ConsoleLog::probe(&mut c), ConsoleLog::LOG32);
+        assert_eq!(c.calls()[1].x1, FfaFunctionId::FfaConsoleLog32.into());
+
+        // Neither supported, or FFA_FEATURES failing, assumes LOG64
+        let mut c = FakeConduit::<16>::from_responses(&[
+            ffa_error(FfaError::NotSupported),
+            ffa_error(FfaError::NotSupported),
+        ]);
+        assert_eq!(ConsoleLog::probe(&mut c), ConsoleLog::LOG64);
+        let mut c = FakeConduit::<16>::from_responses(&[ffa_error(FfaError::Denied)]);
+        assert_eq!(ConsoleLog::probe(&mut c), ConsoleLog::LOG64);
+        assert_eq!(c.calls().len(), 1);
+    }
+
+    #[test]
+    fn failed_calls_count_the_bytes_left() {
+        let _lock = stats_lock();
+        let before = ConsoleStats::get();
+        let mut c =
+            FakeConduit::<16>::from_responses(&[ffa_success(0), ffa_error(FfaError::Denied)]);
+
+        assert_eq!(
+            FfaConsole::new().log_with(&mut c, &ConsoleLog::LOG32, &[b'x'; 60]),
+            Err(FfaError::Denied)
+        );
+        assert_eq!(c.calls().len(), 2);
+
+        let after = ConsoleStats::get();
+        assert_eq!(after.errors - before.errors, 1);
+        assert_eq!(after.dropped_bytes - before.dropped_bytes, 36);
+        assert_eq!(after.nested_prints, before.nested_prints);
+    }
+
+    #[test]
+    fn prints_count_the_pieces_after_a_failure() {
+        let _lock = stats_lock();
+        let before = ConsoleStats::get();
+        let mut c =
+            FakeConduit::<16>::from_responses(&[ffa_error(FfaError::Denied), ffa_success(0)]);
+
+        // Written as "abc", "-", "12345", "-" and "xyz"
+        print_with(
+            &mut c,
+            ConsoleLog::LOG64,
+            Some(4),
+            format_args!("{}-{}-{}", "abc", 12345, "xyz"),
+        );
+        assert_eq!(c.calls().len(), 1);
+
+        let after = ConsoleStats::get();
+        assert_eq!(after.errors - before.errors, 1);
+        assert_eq!(after.dropped_bytes - before.dropped_bytes, 13);
+    }
+
+    #[test]
+    fn nested_prints_are_dropped_per_core() {
+        let _lock = stats_lock();
+        let before = ConsoleStats::get();
+        let mut c = FakeConduit::<16>::from_responses(&[ffa_success(0); 4]);
+        let printing = |key| PRINTING.iter().any(|s| s.load(Ordering::Relaxed) == key);
+
+        // Core 0x100 (cluster 1, core 0) is printing
+        PRINTING[3].store(0x101, Ordering::Relaxed);
+        print_with(
+            &mut c,
+            ConsoleLog::LOG64,
+            Some(0x100),
+            format_args!("nested"),
+        );
+        assert!(c.calls().is_empty());
+
+        print_with(&mut c, ConsoleLog::LOG64, Some(0), format_args!("other"));
+        assert_eq!(c.calls().len(), 1);
+        assert_eq!(c.calls()[0].x1, 5);
+        assert!(!printing(1));
+
+        // Prints from unknown cores aren't guarded
+        print_with(&mut c, ConsoleLog::LOG64, None, format_args!("host"));
+        assert_eq!(c.calls().len(), 2);
+
+        PRINTING[3].store(0, Ordering::Relaxed);
+        print_with(
+            &mut c,
+            ConsoleLog::LOG64,
+            Some(0x100),
+            format_args!("again"),
+        );
+        assert_eq!(c.calls().len(), 3);
+        assert!(!printing(0x101));
+
+        // Without free slots, prints go through unguarded
+        for slot in &PRINTING {
+            slot.store(u64::MAX, Ordering::Relaxed);
+        }
+        print_with(&mut c, ConsoleLog::LOG64, Some(0), format_args!("full"));
+        assert_eq!(c.calls().len(), 4);
+        for slot in &PRINTING {
+            slot.store(0, Ordering::Relaxed);
+        }
+
+        let after = ConsoleStats::get();
+        assert_eq!(after.nested_prints - before.nested_prints, 1);
+        assert_eq!(after.errors, before.errors);
+        assert_eq!(after.dropped_bytes, before.dropped_bytes);
+    }
+}
@@ -0,0 +1,543 @@
+//! Single-threaded async executor driven by FF-A events.
+//!
+//! Partition services are written as `async fn`s awaiting the futures of a
+//! [`Reactor`]: incoming direct requests, notifications, interrupts, yields
+//! and indirect queue messages. The [`Executor`] polls its tasks until none can make
+//! progress, then blocks in FFA_MSG_WAIT, or FFA_YIELD when a task asked
+//! for it, and feeds the event it returns with to the reactor.
+//!
+//! Tasks are futures pinned by the caller, e.g. with [`core::pin::pin!`], so
+//! no allocator is needed:
+//!
+//! ```ignore
+//! let reactor = Reactor::new(id, vcpu_id, npi);
+//! let mut echo = pin!(async {
+//!     loop {
+//!         let req = reactor.request().await;
+//!         reactor.respond(&req, Ok(req.clone()));
+//!     }
+//! });
+//!
+//! let mut executor = Executor::<_, 1>::new(&reactor);
+//! executor.spawn(echo.as_mut())?;
+//! executor.run()
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+
+use super::indirect::IndirectQueue;
+use super::msg::FfaMsg;
+use super::notify::{NotificationBitmap, Notifications, PendingNotifications};
+use super::time::Instant;
+use super::yld::{FfaYield, YieldResult};
+use super::{ffa_result, Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+/// Event state shared by the executor and the futures of its tasks.
+pub struct Reactor<C: Conduit = SmcConduit> {
+    conduit: RefCell<C>,
+    id: u16,
+    vcpu_id: u16,
+    notification_interrupt: Option<u32>,
+    request: RefCell<Option<FfaMsg>>,
+    response: RefCell<Option<FfaMsg>>,
+    interrupt: Cell<Option<u32>>,
+    pending: Cell<PendingNotifications>,
+    yield_timeout: Cell<Option<Duration>>,
+    /// Bumped each time the partition runs again after yielding
+    resumes: Cell<u32>,
+    woken: AtomicBool,
+}
+
+impl Reactor<SmcConduit> {
+    pub fn new(id: u16, vcpu_id: u16, notification_interrupt: Option<u32>) -> Self {
+        Self::with_conduit(SmcConduit, id, vcpu_id, notification_interrupt)
+    }
+}
+
+impl<C: Conduit> Reactor<C> {
+    pub fn with_conduit(
+        conduit: C,
+        id: u16,
+        vcpu_id: u16,
+        notification_interrupt: Option<u32>,
+    ) -> Self {
+        Self {
+            conduit: RefCell::new(conduit),
+            id,
+            vcpu_id,
+            notification_interrupt,
+            request: RefCell::new(None),
+            response: RefCell::new(None),
+            interrupt: Cell::new(None),
+            pending: Cell::new(PendingNotifications::default()),
+            yield_timeout: Cell::new(None),
+            resumes: Cell::new(0),
+            woken: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits for the next direct request.
+    ///
+    /// Requests no task takes are answered with `NotSupported`.
+    pub fn request(&self) -> impl Future<Output = FfaMsg> + '_ {
+        PollFn(move || self.request.borrow_mut().take())
+    }
+
+    /// Answers `req`, see [`FfaMsg::response_to`]. The response is sent once
+    /// all tasks are waiting.
+    pub fn respond(&self, req: &FfaMsg, result: Result<FfaMsg>) {
+        *self.response.borrow_mut() = Some(FfaMsg::response_to(req, result));
+    }
+
+    /// Sends `msg` with FFA_MSG_SEND_DIRECT_REQ2 and resolves to the
+    /// response.
+    ///
+    /// The call blocks the whole partition until the receiver responds.
+    pub fn send_direct_req2<'r>(
+        &'r self,
+        msg: &'r FfaMsg,
+    ) -> impl Future<Output = Result<FfaMsg>> + 'r {
+        PollFn(move || {
+            let req = FfaMsg {
+                function_id: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+                ..msg.clone()
+            };
+            let result = self.conduit.borrow_mut().call((&req).into());
+
+            Some(
+                if result.x0 == FfaFunctionId::FfaMsgSendDirectResp2.into()
+                    || result.x0 == FfaFunctionId::FfaMsgSendDirectResp.into()
+                {
+                    Ok(result.into())
+                } else {
+                    ffa_result(result).and(Err(FfaError::UnknownError))
+                },
+            )
+        })
+    }
+
+    /// Waits for the next FFA_INTERRUPT other than the notification pending
+    /// interrupt, and resolves to its id.
+    ///
+    /// Interrupts no task is waiting for when they arrive are discarded.
+    pub fn interrupt(&self) -> impl Future<Output = u32> + '_ {
+        PollFn(move || self.interrupt.take())
+    }
+
+    /// Waits for any of the partition notifications in `bitmap`, and
+    /// resolves to those that were pending.
+    pub fn notified(
+        &self,
+        bitmap: NotificationBitmap,
+    ) -> impl Future<Output = NotificationBitmap> + '_ {
+        PollFn(move || {
+            let mut pending = self.pending.get();
+            let sp = NotificationBitmap(pending.sp.bits() & bitmap.bits());
+            let vm = NotificationBitmap(pending.vm.bits() & bitmap.bits());
+
+            if (sp | vm).is_empty() {
+                return None;
+            }
+
+            pending.sp = NotificationBitmap(pending.sp.bits() & !sp.bits());
+            pending.vm = NotificationBitmap(pending.vm.bits() & !vm.bits());
+            self.pending.set(pending);
+
+            Some(sp | vm)
+        })
+    }
+
+    /// Yields the CPU for up to `timeout`, see [`YieldResult`].
+    ///
+    /// When several tasks yield at once, the partition yields for the
+    /// shortest timeout and they all resume.
+    pub fn yield_for(&self, timeout: Duration) -> impl Future<Output = YieldResult> + '_ {
+        let mut started: Option<(u32, Instant)> = None;
+
+        PollFn(move || match started {
+            None => {
+                started = Some((self.resumes.get(), Instant::now()));
+                self.request_yield(timeout);
+                None
+            }
+            Some((resumes, _)) if resumes == self.resumes.get() => {
+                self.request_yield(timeout);
+                None
+            }
+            Some((_, start)) => {
+                Some(FfaYield::with_duration(timeout).classify(start, Instant::now()))
+            }
+        })
+    }
+
+    /// Waits for message `seq_num` in `queue` and copies it into `buf`,
+    /// resolving to its length.
+    ///
+    /// The queue is checked each time the partition receives an event, the
+    /// writer is expected to raise a notification after queueing messages.
+    pub fn pop<'r>(
+        &'r self,
+        queue: &'r IndirectQueue<'_>,
+        seq_num: u16,
+        buf: &'r mut [u8],
+    ) -> impl Future<Output = Result<usize>> + 'r {
+        PollFn(move || match queue.pop(seq_num, buf) {
+            Err(FfaError::Retry) => None,
+            result => Some(result),
+        })
+    }
+
+    fn request_yield(&self, timeout: Duration) {
+        let timeout = match self.yield_timeout.get() {
+            Some(current) => current.min(timeout),
+            None => timeout,
+        };
+        self.yield_timeout.set(Some(timeout));
+    }
+
+    fn waker(&self) -> Waker {
+        let data = &self.woken as *const AtomicBool as *const ();
+        // SAFETY: the vtable only ever accesses the AtomicBool, which lives
+        // as long as the reactor the tasks borrow
+        unsafe { Waker::from_raw(RawWaker::new(data, &WAKER_VTABLE)) }
+    }
+
+    /// Blocks until the next event and records it.
+    fn wait(&self) -> Result<()> {
+        let yield_timeout = self.yield_timeout.take();
+        let call = match (self.response.borrow_mut().take(), yield_timeout) {
+            (Some(resp), _) => (&resp).into(),
+            (None, Some(timeout)) => (&FfaYield::with_duration(timeout)).into(),
+            (None, None) => FfaParams {
+                x0: FfaFunctionId::FfaMsgWait.into(),
+                ..Default::default()
+            },
+        };
+
+        let event = self.conduit.borrow_mut().call(call);
+        let id = event.x0;
+
+        if call.x0 == FfaFunctionId::FfaMsgYield.into() {
+            self.resumes.set(self.resumes.get().wrapping_add(1));
+        }
+
+        if id == FfaFunctionId::FfaMsgSendDirectReq.into()
+            || id == FfaFunctionId::FfaMsgSendDirectReq2.into()
+        {
+            *self.request.borrow_mut() = Some(event.into());
+        } else if id == FfaFunctionId::FfaInterrupt.into() {
+            let irq = event.x2 as u32;
+
+            if Some(irq) == self.notification_interrupt {
+                self.fetch_notifications()?;
+            } else {
+                self.interrupt.set(Some(irq));
+            }
+        } else if id == FfaFunctionId::FfaError.into() {
+            return Err((event.x2 as i32 as i64).into());
+        }
+
+        Ok(())
+    }
+
+    fn fetch_notifications(&self) -> Result<()> {
+        let mut conduit = self.conduit.borrow_mut();
+        let new = Notifications::with_conduit(&mut *conduit, self.id)
+            .with_vcpu(self.vcpu_id)
+            .drain()?;
+
+        let mut pending = self.pending.get();
+        pending.sp |= new.sp;
+        pending.vm |= new.vm;
+        pending.spm |= new.spm;
+        pending.hypervisor |= new.hypervisor;
+        self.pending.set(pending);
+
+        Ok(())
+    }
+
+    /// Answers a request no task took, and drops an interrupt no task took.
+    fn reject_unclaimed_events(&self) {
+        self.interrupt.set(None);
+
+        let unclaimed = self.request.borrow_mut().take();
+
+        if let Some(req) = unclaimed {
+            if self.response.borrow().is_none() {
+                self.respond(&req, Err(FfaError::NotSupported));
+            }
+        }
+    }
+}
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &WAKER_VTABLE)
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    (*(data as *const AtomicBool)).store(true, Ordering::Release);
+}
+
+unsafe fn waker_drop(_data: *const ()) {}
+
+/// Future resolving once `f` returns `Some`. Pending futures are polled
+/// again after every event, so they don't need to register the waker.
+struct PollFn<F>(F);
+
+impl<T, F: FnMut() -> Option<T> + Unpin> Future for PollFn<F> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<T> {
+        match (self.0)() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Task of an [`Executor`].
+pub type Task<'a> = Pin<&'a mut dyn Future<Output = ()>>;
+
+/// Runs up to `N` tasks on the calling execution context.
+pub struct Executor<'a, C: Conduit, const N: usize> {
+    reactor: &'a Reactor<C>,
+    tasks: [Option<Task<'a>>; N],
+}
+
+impl<'a, C: Conduit, const N: usize> Executor<'a, C, N> {
+    pub fn new(reactor: &'a Reactor<C>) -> Self {
+        Self {
+            reactor,
+            tasks: core::array::from_fn(|_| None),
+        }
+    }
+
+    /// Adds `task`. Fails with `NoMemory` if all `N` slots are taken.
+    pub fn spawn(&mut self, task: Task<'a>) -> Result<()> {
+        let slot = self
+            .tasks
+            .iter_mut()
+            .find(|t| t.is_none())
+            .ok_or(FfaError::NoMemory)?;
+        *slot = Some(task);
+
+        Ok(())
+    }
+
+    /// Polls every task once, returning whether any is left.
+    pub fn poll_tasks(&mut self) -> bool {
+        let waker = self.reactor.waker();
+        let mut cx = Context::from_waker(&waker);
+
+        for slot in self.tasks.iter_mut() {
+            if let Some(task) = slot {
+                if task.as_mut().poll(&mut cx).is_ready() {
+                    *slot = None;
+                }
+            }
+        }
+
+        self.tasks.iter().any(Option::is_some)
+    }
+
+    /// Runs the tasks until they all complete. Fails on FF-A errors.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            self.reactor.woken.store(false, Ordering::Relaxed);
+
+            if !self.poll_tasks() {
+                return Ok(());
+            }
+
+            if self.reactor.woken.load(Ordering::Acquire) {
+                continue;
+            }
+
+            self.reactor.reject_unclaimed_events();
+            self.reactor.wait()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_error, ffa_success, FakeConduit};
+    use core::pin::pin;
+
+    const SELF_ID: u16 = 0x8001;
+    const NPI: u32 = 7;
+
+    fn reactor(events: &[FfaParams]) -> Reactor<FakeConduit<8>> {
+        Reactor::with_conduit(FakeConduit::from_responses(events), SELF_ID, 2, Some(NPI))
+    }
+
+    fn calls(reactor: &Reactor<FakeConduit<8>>) -> Vec<FfaParams> {
+        reactor.conduit.borrow().calls().to_vec()
+    }
+
+    fn event(id: FfaFunctionId, x2: u64) -> FfaParams {
+        FfaParams {
+            x0: id.into(),
+            x2,
+            ..Default::default()
+        }
+    }
+
+    fn request(id: FfaFunctionId, x4: u64) -> FfaParams {
+        FfaParams {
+            x0: id.into(),
+            x1: 0x8003_8001,
+            x4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn request_and_respond() {
+        let reactor = reactor(&[
+            request(FfaFunctionId::FfaMsgSendDirectReq2, 20),
+            request(FfaFunctionId::FfaMsgSendDirectReq2, 0),
+        ]);
+        let mut task = pin!(async {
+            let req = reactor.request().await;
+            let mut resp = FfaMsg::new();
+            resp.args64[0] = req.args64[0] + 1;
+            reactor.respond(&req, Ok(resp));
+
+            // Resolves with the event the response returned with
+            reactor.request().await;
+        });
+
+        let mut executor = Executor::<_, 1>::new(&reactor);
+        executor.spawn(task.as_mut()).unwrap();
+        executor.run().unwrap();
+
+        let calls = calls(&reactor);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].x0, FfaFunctionId::FfaMsgWait.into());
+        assert_eq!(calls[1].x0, FfaFunctionId::FfaMsgSendDirectResp2.into());
+        assert_eq!(calls[1].x1, 0x8001_8003);
+        assert_eq!(calls[1].x4, 21);
+    }
+
+    #[test]
+    fn unclaimed_requests_are_not_supported() {
+        let reactor = reactor(&[
+            request(FfaFunctionId::FfaMsgSendDirectReq2, 1),
+            event(FfaFunctionId::FfaInterrupt, NPI as u64),
+            ffa_success(1 << 4 | 1 << 9),
+        ]);
+        let mut task = pin!(async {
+            let fired = reactor.notified(NotificationBitmap(1 << 4)).await;
+            assert_eq!(fired, NotificationBitmap(1 << 4));
+        });
+
+        let mut executor = Executor::<_, 1>::new(&reactor);
+        executor.spawn(task.as_mut()).unwrap();
+        executor.run().unwrap();
+
+        let calls = calls(&reactor);
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[1].x0, FfaFunctionId::FfaMsgSendDirectResp2.into());
+        assert_eq!(calls[1].x4, i64::from(FfaError::NotSupported) as u64);
+        assert_eq!(calls[2].x0, FfaFunctionId::FfaNotificationGet.into());
+        assert_eq!(calls[2].x1, 0x0002_8001);
+
+        // Notifications nobody waited for stay pending
+        assert_eq!(reactor.pending.get().sp, NotificationBitmap(1 << 9));
+    }
+
+    #[test]
+    fn tasks_yield_for_the_shortest_timeout() {
+        let reactor = reactor(&[ffa_success(0)]);
+        let mut long = pin!(async {
+            reactor.yield_for(Duration::from_nanos(50)).await;
+        });
+        let mut short = pin!(async {
+            // The host clock doesn't move, so the timeout never elapses
+            let result = reactor.yield_for(Duration::from_nanos(5)).await;
+            assert_eq!(result, YieldResult::Event);
+        });
+
+        let mut executor = Executor::<_, 2>::new(&reactor);
+        executor.spawn(long.as_mut()).unwrap();
+        executor.spawn(short.as_mut()).unwrap();
+        executor.run().unwrap();
+
+        let calls = calls(&reactor);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].x0, FfaFunctionId::FfaMsgYield.into());
+        assert_eq!((calls[0].x2, calls[0].x3), (5, 0));
+    }
+
+    #[test]
+    fn zero_yield_timeout_is_rounded_up() {
+        let reactor = reactor(&[ffa_success(0)]);
+        let mut task = pin!(async {
+            reactor.yield_for(Duration::ZERO).await;
+        });
+
+        let mut executor = Executor::<_, 1>::new(&reactor);
+        executor.spawn(task.as_mut()).unwrap();
+        executor.run().unwrap();
+
+        assert_eq!((calls(&reactor)[0].x2, calls(&reactor)[0].x3), (1, 0));
+    }
+
+    #[test]
+    fn interrupts_reach_waiting_tasks() {
+        let reactor = reactor(&[
+            event(FfaFunctionId::FfaInterrupt, 9),
+            event(FfaFunctionId::FfaInterrupt, 10),
+            request(FfaFunctionId::FfaMsgSendDirectReq2, 0),
+        ]);
+        let mut task = pin!(async {
+            assert_eq!(reactor.interrupt().await, 9);
+
+            // Interrupt 10 arrives while waiting for the request and is
+            // discarded
+            let req = reactor.request().await;
+            reactor.respond(&req, Ok(FfaMsg::new()));
+        });
+
+        let mut executor = Executor::<_, 1>::new(&reactor);
+        executor.spawn(task.as_mut()).unwrap();
+        executor.run().unwrap();
+
+        assert_eq!(calls(&reactor).len(), 3);
+        assert_eq!(reactor.interrupt.get(), None);
+    }
+
+    #[test]
+    fn errors_stop_the_executor() {
+        let reactor = reactor(&[ffa_error(FfaError::Aborted)]);
+        let mut task = pin!(async {
+            reactor.request().await;
+        });
+
+        let mut executor = Executor::<_, 1>::new(&reactor);
+        executor.spawn(task.as_mut()).unwrap();
+        assert_eq!(executor.run(), Err(FfaError::Aborted));
+    }
+
+    #[test]
+    fn spawn_fails_when_full() {
+        let reactor = reactor(&[]);
+        let mut a = pin!(async {});
+        let mut b = pin!(async {});
+
+        let mut executor = Executor::<_, 1>::new(&reactor);
+        executor.spawn(a.as_mut()).unwrap();
+        assert_eq!(executor.spawn(b.as_mut()), Err(FfaError::NoMemory));
+        assert!(!executor.poll_tasks());
+    }
+}
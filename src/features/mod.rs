@@ -1,4 +1,11 @@
-use super::{ffa_smc, FfaError, FfaFunctionId, FfaParams, Result};
+use super::{ffa_result, Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+/// Feature id of the Notification Pending Interrupt
+pub const FEATURE_NPI: u64 = 0x1;
+/// Feature id of the Schedule Receiver Interrupt
+pub const FEATURE_SRI: u64 = 0x2;
+/// Feature id of the Managed Exit interrupt
+pub const FEATURE_MANAGED_EXIT: u64 = 0x3;
 
 #[derive(Default)]
 pub struct FfaFeatures {
@@ -17,23 +24,24 @@ impl FfaFeatures {
     }
 
     pub(crate) fn exec(self) -> Result<Self> {
+        self.exec_with(&mut SmcConduit)
+    }
+
+    /// Queries the feature through `conduit`.
+    pub fn exec_with<C: Conduit>(self, conduit: &mut C) -> Result<Self> {
         let params = FfaParams {
             x0: FfaFunctionId::FfaFeatures.into(),
             x1: self.id(),
+            x2: self.properties(),
             ..Default::default()
         };
 
-        let result = ffa_smc(params);
+        let result = ffa_result(conduit.call(params))?;
 
-        if result.x0 == FfaFunctionId::FfaError.into() {
-            Err(Into::<FfaError>::into(result.x0 as i64))
-        } else {
-            Ok(Self {
-                _id: result.x0,
-                _properties: result.x1,
-                _interface_properties: result.x2,
-            })
-        }
+        Ok(Self {
+            _interface_properties: result.x2,
+            ..self
+        })
     }
 
     pub fn id(&self) -> u64 {
@@ -48,6 +56,12 @@ impl FfaFeatures {
         self._interface_properties
     }
 
+    /// Interrupt id returned for the [`FEATURE_NPI`], [`FEATURE_SRI`] and
+    /// [`FEATURE_MANAGED_EXIT`] feature ids.
+    pub fn interrupt_id(&self) -> u32 {
+        self._interface_properties as u32
+    }
+
     pub fn is_feature_id(&self) -> bool {
         self._id & (1 << 31) == 0
     }
@@ -56,3 +70,12 @@ impl FfaFeatures {
         self._id & (1 << 31) != 0
     }
 }
+
+/// Interrupt id of `feature`, or `None` if it isn't available to the caller.
+pub(crate) fn interrupt_id<C: Conduit>(conduit: &mut C, feature: u64) -> Result<Option<u32>> {
+    match FfaFeatures::new(feature, 0).exec_with(conduit) {
+        Ok(features) => Ok(Some(features.interrupt_id())),
+        Err(FfaError::NotSupported) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
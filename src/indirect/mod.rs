@@ -1,11 +1,35 @@
-use core::ptr;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::slice;
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
 
-use super::FfaError;
+use super::notify::{self, NotificationBitmap, Notifications, PendingNotifications};
+use super::{Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
 
 const QUEUE_ENTRY_COUNT: u16 = 0x8;
 const QUEUE_BLOCK_SIZE: usize = 0x100;
 const QUEUE_STATE_FREE: u8 = 0x0;
 const QUEUE_STATE_VALID: u8 = 0x1;
+const QUEUE_STATE_BUSY: u8 = 0x2;
+
+/// Queue layout without fragment metadata
+const QUEUE_VERSION_0: u16 = 0x0;
+/// Bitmap entries record their fragment index and the fragment count and are
+/// claimed through the BUSY state before being accessed. The header records
+/// the block size, and the bitmap spans `count` entries.
+const QUEUE_VERSION_1: u16 = 0x1;
+
+// Byte offsets into the shared region, matching AsyncMsgHeader and AsyncMsgBitmap
+const HEADER_VERSION: usize = 0;
+const HEADER_COUNT: usize = 2;
+const HEADER_BLOCK_SIZE: usize = 4;
+const HEADER_BITMAP: usize = 8;
+const BITMAP_SEQ_NUM: usize = 0;
+const BITMAP_LENGTH: usize = 2;
+const BITMAP_STATE: usize = 4;
+const BITMAP_FRAGMENT: usize = 5;
+const BITMAP_FRAGMENT_COUNT: usize = 6;
+const BITMAP_ENTRY_SIZE: usize = 8;
 
 #[repr(C, packed)]
 #[derive(Default)]
@@ -13,36 +37,643 @@ pub struct AsyncMsgBitmap {
     pub seq_num: u16,
     pub length: u16,
     pub state: u8,
-    pub res0: u8,
-    pub res1: u16,
+    /// Index of this block within its message
+    pub fragment: u8,
+    /// Number of blocks making up the message, 0 in version 0 queues
+    pub fragment_count: u16,
 }
 
 #[repr(C, packed)]
-#[derive(Default)]
-pub struct AsyncMsgHeader {
+pub struct AsyncMsgHeader<const N: usize = 8> {
     pub version: u16,
     pub count: u16,
-    pub res0: u32,
-    pub bitmap: [AsyncMsgBitmap; 8],
+    /// Size of each block, reserved in version 0
+    pub block_size: u32,
+    pub bitmap: [AsyncMsgBitmap; N],
 }
 
-#[derive(Default)]
-pub struct FfaIndirectMsg {}
+impl<const N: usize> Default for AsyncMsgHeader<N> {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            count: 0,
+            block_size: 0,
+            bitmap: core::array::from_fn(|_| AsyncMsgBitmap::default()),
+        }
+    }
+}
+
+/// Layout of an indirect queue: the number of bitmap entries and the size
+/// of the blocks, the first of which holds the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueGeometry {
+    pub block_size: usize,
+    pub entry_count: u16,
+}
+
+impl Default for QueueGeometry {
+    fn default() -> Self {
+        Self {
+            block_size: QUEUE_BLOCK_SIZE,
+            entry_count: QUEUE_ENTRY_COUNT,
+        }
+    }
+}
+
+impl QueueGeometry {
+    /// Largest number of entries a queue can have.
+    pub const MAX_ENTRY_COUNT: u16 = 64;
+
+    /// Size of the region holding a queue with this geometry.
+    pub const fn region_size(&self) -> usize {
+        self.block_size * (self.entry_count as usize + 1)
+    }
+
+    /// Geometry with as many `block_size` entries as fit in `region_len`.
+    pub fn for_region(region_len: usize, block_size: usize) -> Result<Self> {
+        let entries = (region_len / block_size.max(1)).saturating_sub(1);
+        let geometry = Self {
+            block_size,
+            entry_count: entries.min(Self::MAX_ENTRY_COUNT as usize) as u16,
+        };
+
+        geometry.validate()?;
+        Ok(geometry)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let header_size = HEADER_BITMAP + BITMAP_ENTRY_SIZE * self.entry_count as usize;
+
+        if self.entry_count == 0
+            || self.entry_count > Self::MAX_ENTRY_COUNT
+            || self.block_size % 8 != 0
+            || self.block_size < header_size
+            // Block lengths are recorded in 16 bits
+            || self.block_size > u16::MAX as usize
+        {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        Ok(())
+    }
+}
+
+/// FF-A notification raised towards the reader each time a message is
+/// queued.
+///
+/// The notification must have been bound by the receiver to the sender with
+/// FFA_NOTIFICATION_BIND.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueNotifier {
+    pub sender_id: u16,
+    pub receiver_id: u16,
+    pub bitmap: NotificationBitmap,
+    /// FFA_NOTIFICATION_SET flags, see [`notify::SET_PER_VCPU`].
+    pub flags: u32,
+    /// Delays the Schedule Receiver Interrupt until the sender yields.
+    pub delay_sri: bool,
+}
+
+impl QueueNotifier {
+    /// Fails with `InvalidParameters` if `notification_id` is not below 64.
+    pub fn new(sender_id: u16, receiver_id: u16, notification_id: u8) -> Result<Self> {
+        Ok(Self {
+            sender_id,
+            receiver_id,
+            bitmap: NotificationBitmap::from_id(notification_id)?,
+            flags: 0,
+            delay_sri: false,
+        })
+    }
+
+    /// Raises the notification through `conduit`.
+    pub fn notify<C: Conduit>(&self, conduit: &mut C) -> Result<()> {
+        Notifications::with_conduit(conduit, self.sender_id).set(
+            self.receiver_id,
+            self.flags,
+            self.bitmap,
+            self.delay_sri,
+        )
+    }
+
+    /// Whether `pending` includes this notification.
+    pub fn is_pending(&self, pending: &PendingNotifications) -> bool {
+        pending.partition().intersects(self.bitmap)
+    }
+}
+
+/// What [`IndirectQueue::recv`] returned with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvEvent {
+    /// The message was copied out, with its length.
+    Message(usize),
+    /// Notifications other than the queue's were retrieved while waiting.
+    /// The queue's own is left out.
+    Notifications(PendingNotifications),
+    /// FFA_MSG_WAIT returned with another event, e.g. a direct request or
+    /// an interrupt other than the notification pending interrupt.
+    Event(FfaParams),
+}
+
+/// Typed view over the indirect message queue in a shared memory region.
+///
+/// The region starts with an [`AsyncMsgHeader`] block followed by one data
+/// block per bitmap entry.
+///
+/// Each bitmap entry is owned through its `state` byte, which is only ever
+/// changed with atomic operations. A producer claims free entries by moving
+/// them to `BUSY`, fills them in and publishes them as `VALID` with release
+/// ordering. A consumer claims a complete message by moving its entries from
+/// `VALID` to `BUSY`, copies it out and hands the entries back as `FREE`.
+/// Both sides may therefore run concurrently, on different cores and in
+/// different security states, as long as the peer follows the same protocol.
+pub struct IndirectQueue<'a> {
+    base: NonNull<u8>,
+    geometry: QueueGeometry,
+    notifier: Option<QueueNotifier>,
+    _region: PhantomData<&'a mut [u8]>,
+}
+
+// SAFETY: all accesses to the shared region go through atomic state
+// transitions that give the accessing context exclusive ownership of an entry.
+unsafe impl Send for IndirectQueue<'_> {}
+unsafe impl Sync for IndirectQueue<'_> {}
+
+impl<'a> IndirectQueue<'a> {
+    /// Size of the shared region holding a queue with the default geometry.
+    pub const REGION_SIZE: usize = QUEUE_BLOCK_SIZE * (QUEUE_ENTRY_COUNT as usize + 1);
+
+    /// Required alignment of the shared region.
+    pub const REGION_ALIGN: usize = 8;
+
+    fn validate(region: &'a mut [u8], geometry: QueueGeometry) -> Result<Self> {
+        geometry.validate()?;
+
+        if region.len() < geometry.region_size()
+            || region.as_ptr() as usize % Self::REGION_ALIGN != 0
+        {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        Ok(Self {
+            base: NonNull::from(region).cast(),
+            geometry,
+            notifier: None,
+            _region: PhantomData,
+        })
+    }
+
+    /// Zeroes `region` and lays out an empty queue with the default
+    /// geometry in it.
+    pub fn init(region: &'a mut [u8]) -> Result<Self> {
+        Self::init_with(region, QueueGeometry::default())
+    }
+
+    /// Zeroes `region` and lays out an empty queue with `geometry` in it.
+    pub fn init_with(region: &'a mut [u8], geometry: QueueGeometry) -> Result<Self> {
+        let queue = Self::validate(region, geometry)?;
+
+        // SAFETY: validate() checked the region spans the whole queue
+        unsafe {
+            ptr::write_bytes(queue.base.as_ptr(), 0, geometry.region_size());
+        }
+        queue.write_u16(HEADER_VERSION, QUEUE_VERSION_1);
+        queue.write_u16(HEADER_COUNT, geometry.entry_count);
+        queue.write_u32(HEADER_BLOCK_SIZE, geometry.block_size as u32);
+        dmb();
+
+        Ok(queue)
+    }
+
+    /// Wraps a region already holding a queue, e.g. one set up by the peer.
+    pub fn attach(region: &'a mut [u8]) -> Result<Self> {
+        let geometry = Self::header_geometry(region)?;
+        Self::validate(region, geometry)
+    }
+
+    /// Reads the geometry recorded in the queue header at the start of `region`.
+    fn header_geometry(region: &[u8]) -> Result<QueueGeometry> {
+        let header = region
+            .get(..HEADER_BITMAP)
+            .ok_or(FfaError::InvalidParameters)?;
+        let version = u16::from_le_bytes([header[0], header[1]]);
+        let count = u16::from_le_bytes([header[2], header[3]]);
+
+        match version {
+            QUEUE_VERSION_0 if count == QUEUE_ENTRY_COUNT => Ok(QueueGeometry::default()),
+            QUEUE_VERSION_1 => Ok(QueueGeometry {
+                block_size: u32::from_le_bytes([header[4], header[5], header[6], header[7]])
+                    as usize,
+                entry_count: count,
+            }),
+            _ => Err(FfaError::InvalidParameters),
+        }
+    }
+
+    pub fn geometry(&self) -> QueueGeometry {
+        self.geometry
+    }
+
+    /// Raises `notifier` after each message pushed to the queue.
+    pub fn with_notifier(mut self, notifier: QueueNotifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn notifier(&self) -> Option<&QueueNotifier> {
+        self.notifier.as_ref()
+    }
+
+    /// Queues `data` under `seq_num`, split into as many blocks as needed.
+    ///
+    /// Fails with `NoMemory`, leaving the queue untouched, if there aren't
+    /// enough free blocks. If the queue has a notifier, it is raised once the
+    /// message is published.
+    pub fn push(&self, seq_num: u16, data: &[u8]) -> Result<()> {
+        self.push_with(&mut SmcConduit, seq_num, data)
+    }
+
+    /// Same as [`IndirectQueue::push`], raising the notifier through `conduit`.
+    ///
+    /// The message stays queued if raising the notification fails.
+    pub fn push_with<C: Conduit>(&self, conduit: &mut C, seq_num: u16, data: &[u8]) -> Result<()> {
+        let needed = data.len().div_ceil(self.geometry.block_size).max(1);
+        if needed > u8::MAX as usize + 1 {
+            return Err(FfaError::NoMemory);
+        }
+
+        let mut claimed = 0u64;
+        for index in self.indices() {
+            if claimed.count_ones() as usize == needed {
+                break;
+            }
+            if self.transition(index, QUEUE_STATE_FREE, QUEUE_STATE_BUSY) {
+                claimed |= 1 << index;
+            }
+        }
+
+        if (claimed.count_ones() as usize) < needed {
+            self.release(claimed, QUEUE_STATE_FREE);
+            return Err(FfaError::NoMemory);
+        }
+
+        let mut fragments = data.chunks(self.geometry.block_size);
+        for (fragment, index) in self.entries(claimed).enumerate() {
+            let chunk = fragments.next().unwrap_or(&[]);
+
+            // SAFETY: the entry is BUSY, so its block belongs to us
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), self.block(index), chunk.len());
+            }
+
+            self.write_meta(index, BITMAP_LENGTH, chunk.len() as u16);
+            self.write_meta(index, BITMAP_SEQ_NUM, seq_num);
+            self.write_meta(index, BITMAP_FRAGMENT_COUNT, needed as u16);
+            self.fragment(index)
+                .store(fragment as u8, Ordering::Release);
+        }
+
+        // Data and bitmap entries must be visible before the entries are
+        // published, whatever the peer's view of the memory attributes.
+        dmb();
+        self.release(claimed, QUEUE_STATE_VALID);
+
+        match &self.notifier {
+            Some(notifier) => notifier.notify(conduit),
+            None => Ok(()),
+        }
+    }
+
+    /// Total length of the message queued under `seq_num`.
+    ///
+    /// Fails with `Retry` if the message hasn't been completely queued yet.
+    pub fn message_len(&self, seq_num: u16) -> Result<usize> {
+        let entries = self.message_entries(seq_num)?;
+        Ok(self.entries(entries).map(|i| self.entry_length(i)).sum())
+    }
+
+    /// Dequeues the message queued under `seq_num`, reassembling its blocks
+    /// into `buf`, and returns its length.
+    ///
+    /// Fails with `Retry` if the message hasn't been completely queued yet
+    /// or is being dequeued by someone else, and with `NoMemory` if it
+    /// doesn't fit in `buf`. The message stays queued in all three cases.
+    pub fn pop(&self, seq_num: u16, buf: &mut [u8]) -> Result<usize> {
+        let entries = self.message_entries(seq_num)?;
+
+        let mut claimed = 0u64;
+        for index in self.entries(entries) {
+            if !self.transition(index, QUEUE_STATE_VALID, QUEUE_STATE_BUSY) {
+                self.release(claimed, QUEUE_STATE_VALID);
+                return Err(FfaError::Retry);
+            }
+            claimed |= 1 << index;
+        }
+
+        // Another consumer may have swapped the message for a different one
+        // between the scan and the claim, so check it again now that we own it.
+        dmb();
+        if self.message_entries_in(seq_num, QUEUE_STATE_BUSY, claimed) != Ok(claimed) {
+            self.release(claimed, QUEUE_STATE_VALID);
+            return Err(FfaError::Retry);
+        }
+
+        let len = match self.copy_message(seq_num, claimed, buf) {
+            Ok(len) => len,
+            Err(err) => {
+                self.release(claimed, QUEUE_STATE_VALID);
+                return Err(err);
+            }
+        };
+
+        for index in self.entries(claimed) {
+            self.write_meta(index, BITMAP_LENGTH, 0);
+            self.write_meta(index, BITMAP_SEQ_NUM, 0);
+            self.write_meta(index, BITMAP_FRAGMENT_COUNT, 0);
+            self.fragment(index).store(0, Ordering::Release);
+        }
+
+        // Our reads of the blocks must complete before the peer may reuse them
+        dmb();
+        self.release(claimed, QUEUE_STATE_FREE);
+
+        Ok(len)
+    }
+
+    /// Copies the fragments of message `seq_num`, whose `claimed` entries we
+    /// own, into `buf` in order and returns the message length.
+    fn copy_message(&self, seq_num: u16, claimed: u64, buf: &mut [u8]) -> Result<usize> {
+        let mut offset = 0;
+
+        for fragment in 0..self.entries(claimed).count() {
+            let index = self
+                .find_fragment(seq_num, fragment as u16, QUEUE_STATE_BUSY, claimed)
+                .ok_or(FfaError::Retry)?;
+
+            // Read once, the peer may still write to the bitmap
+            let length = self.entry_length(index);
+            let dest = buf
+                .get_mut(offset..offset + length)
+                .ok_or(FfaError::NoMemory)?;
+
+            // SAFETY: the entry is BUSY, so its block belongs to us, and
+            // entry_length() is bounded by the block size
+            let block = unsafe { slice::from_raw_parts(self.block(index), length) };
+            dest.copy_from_slice(block);
+            offset += length;
+        }
+
+        Ok(offset)
+    }
+
+    /// Dequeues the message queued under `seq_num`, blocking in FFA_MSG_WAIT
+    /// until the queue's notifier is raised while it isn't available.
+    ///
+    /// The queue must have been given the notifier the writer raises. Any
+    /// other event FFA_MSG_WAIT returns with, and the other partition
+    /// notifications retrieved on an interrupt, are handed back to the
+    /// caller, who calls `recv` again once it has handled them.
+    pub fn recv<C: Conduit>(
+        &self,
+        conduit: &mut C,
+        vcpu_id: u16,
+        seq_num: u16,
+        buf: &mut [u8],
+    ) -> Result<RecvEvent> {
+        let notifier = self.notifier.ok_or(FfaError::InvalidParameters)?;
+
+        loop {
+            match self.pop(seq_num, buf) {
+                Err(FfaError::Retry) => {}
+                result => return result.map(RecvEvent::Message),
+            }
+
+            let event = conduit.call(FfaParams {
+                x0: FfaFunctionId::FfaMsgWait.into(),
+                ..Default::default()
+            });
+            if event.x0 != FfaFunctionId::FfaInterrupt.into() {
+                return Ok(RecvEvent::Event(event));
+            }
+
+            // FFA_NOTIFICATION_GET clears all the pending notifications, the
+            // queue's is consumed here and the others go back to the caller.
+            // The queue is checked again whether it was pending or not.
+            let mut pending = Notifications::with_conduit(&mut *conduit, notifier.receiver_id)
+                .get(vcpu_id, notify::GET_FROM_SP | notify::GET_FROM_VM)?;
+            let ours = pending.partition().intersects(notifier.bitmap);
+            let others = !notifier.bitmap.bits();
+            pending.sp = NotificationBitmap(pending.sp.bits() & others);
+            pending.vm = NotificationBitmap(pending.vm.bits() & others);
+
+            if !pending.partition().is_empty() {
+                return Ok(RecvEvent::Notifications(pending));
+            }
+            if !ours {
+                // Not the notification pending interrupt
+                return Ok(RecvEvent::Event(event));
+            }
+        }
+    }
+
+    /// Bit mask of the entries holding the complete message `seq_num`.
+    fn message_entries(&self, seq_num: u16) -> Result<u64> {
+        let entries = self.message_entries_in(seq_num, QUEUE_STATE_VALID, u64::MAX)?;
+
+        // Pairs with the barrier issued by the producer before publishing
+        dmb();
+        Ok(entries)
+    }
+
+    fn message_entries_in(&self, seq_num: u16, state: u8, mask: u64) -> Result<u64> {
+        let count = self
+            .indices()
+            .filter(|&i| mask & (1 << i) != 0)
+            .find(|&i| self.is_entry(i, seq_num, state))
+            .map(|i| {
+                let count = self.read_meta(i, BITMAP_FRAGMENT_COUNT);
+                // Version 0 queues only carry single block messages
+                count.clamp(1, u8::MAX as u16 + 1)
+            })
+            .ok_or(FfaError::Retry)?;
+
+        (0..count).try_fold(0u64, |entries, fragment| {
+            self.find_fragment(seq_num, fragment, state, mask)
+                .map(|index| entries | 1 << index)
+                .ok_or(FfaError::Retry)
+        })
+    }
+
+    fn find_fragment(&self, seq_num: u16, fragment: u16, state: u8, mask: u64) -> Option<usize> {
+        self.indices().find(|&i| {
+            mask & (1 << i) != 0
+                && self.is_entry(i, seq_num, state)
+                && self.fragment(i).load(Ordering::Acquire) as u16 == fragment
+        })
+    }
+
+    fn is_entry(&self, index: usize, seq_num: u16, state: u8) -> bool {
+        self.state(index).load(Ordering::Acquire) == state
+            && self.read_meta(index, BITMAP_SEQ_NUM) == seq_num
+    }
+
+    fn indices(&self) -> core::ops::Range<usize> {
+        0..self.geometry.entry_count as usize
+    }
+
+    fn entries(&self, mask: u64) -> impl Iterator<Item = usize> {
+        self.indices().filter(move |i| mask & (1 << i) != 0)
+    }
+
+    fn transition(&self, index: usize, from: u8, to: u8) -> bool {
+        self.state(index)
+            .compare_exchange(from, to, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn release(&self, mask: u64, state: u8) {
+        for index in self.entries(mask) {
+            self.state(index).store(state, Ordering::Release);
+        }
+    }
+
+    fn entry_length(&self, index: usize) -> usize {
+        (self.read_meta(index, BITMAP_LENGTH) as usize).min(self.geometry.block_size)
+    }
+
+    fn entry_offset(index: usize) -> usize {
+        HEADER_BITMAP + index * BITMAP_ENTRY_SIZE
+    }
+
+    fn state(&self, index: usize) -> &AtomicU8 {
+        // SAFETY: the offset lies within the header block and AtomicU8 has
+        // the same size and alignment as u8.
+        unsafe { &*(self.at(Self::entry_offset(index) + BITMAP_STATE) as *const AtomicU8) }
+    }
+
+    fn fragment(&self, index: usize) -> &AtomicU8 {
+        // SAFETY: see state()
+        unsafe { &*(self.at(Self::entry_offset(index) + BITMAP_FRAGMENT) as *const AtomicU8) }
+    }
+
+    /// 16-bit field of a bitmap entry, accessed as a whole as the peer may
+    /// update it concurrently.
+    fn meta(&self, index: usize, field: usize) -> &AtomicU16 {
+        let ptr = self.at(Self::entry_offset(index) + field);
+        debug_assert!(ptr as usize % 2 == 0);
+        // SAFETY: the offset lies within the header block, and the fields
+        // are 2-byte aligned in the REGION_ALIGN aligned region.
+        unsafe { &*(ptr as *const AtomicU16) }
+    }
+
+    /// Reads an entry field, ordered like the state byte.
+    fn read_meta(&self, index: usize, field: usize) -> u16 {
+        u16::from_le(self.meta(index, field).load(Ordering::Acquire))
+    }
+
+    fn write_meta(&self, index: usize, field: usize, value: u16) {
+        self.meta(index, field)
+            .store(value.to_le(), Ordering::Release);
+    }
+
+    fn block(&self, index: usize) -> *mut u8 {
+        self.at(self.geometry.block_size * (index + 1))
+    }
+
+    fn at(&self, offset: usize) -> *mut u8 {
+        debug_assert!(offset < self.geometry.region_size());
+        // SAFETY: offsets are bounded by the region size, checked in validate()
+        unsafe { self.base.as_ptr().add(offset) }
+    }
+
+    fn write_u8(&self, offset: usize, value: u8) {
+        // SAFETY: see at()
+        unsafe { ptr::write_volatile(self.at(offset), value) }
+    }
+
+    fn write_u16(&self, offset: usize, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write_u8(offset, lo);
+        self.write_u8(offset + 1, hi);
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            self.write_u8(offset + i, byte);
+        }
+    }
+}
+
+/// Orders accesses to the shared region for observers in any shareability
+/// domain, including a normal world mapping with different attributes.
+#[inline(always)]
+fn dmb() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("dmb sy", options(nostack, preserves_flags));
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    core::sync::atomic::fence(Ordering::SeqCst);
+}
+
+fn into_status<T>(result: Result<T>) -> FfaError {
+    match result {
+        Ok(_) => FfaError::Ok,
+        Err(err) => err,
+    }
+}
+
+/// Attaches to the queue in the `region_len` bytes mapped at `base_addr`.
+///
+/// The geometry comes from the header, which the peer controls, so it is
+/// only accepted if the queue fits in the mapping.
+///
+/// # Safety
+///
+/// `base_addr` must point to `region_len` mapped bytes, valid for `'a`.
+unsafe fn attach_at<'a>(base_addr: u64, region_len: usize) -> Result<IndirectQueue<'a>> {
+    let region = slice::from_raw_parts_mut(base_addr as *mut u8, region_len);
+    IndirectQueue::attach(region)
+}
+
+/// Indirect queue access through raw addresses.
+pub struct FfaIndirectMsg {
+    region_len: usize,
+}
+
+impl Default for FfaIndirectMsg {
+    fn default() -> Self {
+        Self {
+            region_len: IndirectQueue::REGION_SIZE,
+        }
+    }
+}
 
 impl FfaIndirectMsg {
+    /// Accesses queues mapped over [`IndirectQueue::REGION_SIZE`] bytes.
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Accesses queues mapped over `region_len` bytes, e.g. ones set up by
+    /// [`FfaIndirectMsg::init_indirect_msg`] in a larger region.
+    pub fn with_region_len(region_len: usize) -> Self {
+        Self { region_len }
+    }
+
+    /// Lays out an empty queue with as many default sized blocks as fit in
+    /// `length` bytes.
+    ///
     /// # Safety
     ///
     /// This function directly initialzes physical memory base_addr
     pub unsafe fn init_indirect_msg(&self, base_addr: u64, length: usize) -> FfaError {
-        ptr::write_bytes(base_addr as *mut u8, 0, length);
-
-        let asyncmsg = base_addr as *mut AsyncMsgHeader;
-        (*asyncmsg).count = QUEUE_ENTRY_COUNT;
-        FfaError::Ok
+        let region = slice::from_raw_parts_mut(base_addr as *mut u8, length);
+        into_status(
+            QueueGeometry::for_region(length, QUEUE_BLOCK_SIZE)
+                .and_then(|geometry| IndirectQueue::init_with(region, geometry)),
+        )
     }
 
     /// # Safety
@@ -52,74 +683,464 @@ impl FfaIndirectMsg {
         &self,
         base_addr: u64,
         seq_num: u16,
-        buf: &mut [u8; QUEUE_BLOCK_SIZE],
+        buf: &mut [u8],
     ) -> FfaError {
-        let asyncmsg = base_addr as *mut AsyncMsgHeader;
-        let entry_count = (*asyncmsg).count as usize;
-
-        for (index, b) in (*asyncmsg).bitmap.iter_mut().enumerate() {
-            // Make sure we don't go beyond entry_count
-            if index == entry_count {
-                return FfaError::NoMemory;
-            }
-
-            let sn = b.seq_num;
-            if sn == seq_num && b.state == QUEUE_STATE_VALID {
-                let length = b.length;
-                ptr::copy_nonoverlapping(
-                    &((*asyncmsg).bitmap[index]) as *const AsyncMsgBitmap as *const u8,
-                    buf.as_mut_ptr(),
-                    length as usize,
-                );
-                b.length = 0;
-                b.state = QUEUE_STATE_FREE;
-                b.seq_num = 0;
-                return FfaError::Ok;
-            }
-        }
-
-        FfaError::Retry
+        into_status(attach_at(base_addr, self.region_len).and_then(|q| q.pop(seq_num, buf)))
     }
 
     /// # Safety
     ///
     /// This function directly reads physical memory pointed to by base_addr
     pub unsafe fn write_indirect_msg(&self, base_addr: u64, seq_num: u16, buf: &[u8]) -> FfaError {
-        // Note if we have multi-threaded support we need to take a lock while accessing the queue
-        let asyncmsg = base_addr as *mut AsyncMsgHeader;
-        let entry_count = (*asyncmsg).count as usize;
-        let mut data_len = buf.len();
-        let mut data_index: usize = 0;
-
-        for (bm_index, b) in (*asyncmsg).bitmap.iter_mut().enumerate() {
-            // Make sure we don't iterate beyond entry_count
-            if bm_index == entry_count {
-                return FfaError::NoMemory;
-            }
+        into_status(attach_at(base_addr, self.region_len).and_then(|q| q.push(seq_num, buf)))
+    }
+}
 
-            let state = b.state;
-            if state == QUEUE_STATE_FREE {
-                // Copy data first then update the header structure
-                let mut copy_len = QUEUE_BLOCK_SIZE;
-                let data_base = base_addr + (QUEUE_BLOCK_SIZE * (bm_index + 1)) as u64;
-                if data_len < copy_len {
-                    copy_len = data_len;
-                }
-                ptr::copy_nonoverlapping(&buf[data_index], data_base as *mut u8, copy_len);
-                data_index += copy_len;
-                data_len -= copy_len;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_success, FakeConduit};
+
+    #[repr(C, align(8))]
+    struct Region([u8; IndirectQueue::REGION_SIZE + 8]);
+
+    fn region() -> Box<Region> {
+        Box::new(Region([0xaa; IndirectQueue::REGION_SIZE + 8]))
+    }
+
+    #[test]
+    fn rejects_short_or_misaligned_regions() {
+        let mut r = region();
+        assert_eq!(
+            IndirectQueue::init(&mut r.0[..IndirectQueue::REGION_SIZE - 1]).err(),
+            Some(FfaError::InvalidParameters)
+        );
+        assert_eq!(
+            IndirectQueue::init(&mut r.0[1..]).err(),
+            Some(FfaError::InvalidParameters)
+        );
+    }
+
+    #[test]
+    fn attach_requires_initialized_header() {
+        let mut r = region();
+        assert!(IndirectQueue::attach(&mut r.0).is_err());
+
+        IndirectQueue::init(&mut r.0).unwrap();
+        assert!(IndirectQueue::attach(&mut r.0).is_ok());
+    }
+
+    #[test]
+    fn push_pop_round_trip() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        let mut buf = [0u8; QUEUE_BLOCK_SIZE];
 
-                b.length = copy_len as u16;
-                b.state = QUEUE_STATE_VALID;
-                b.seq_num = seq_num;
+        q.push(7, b"hello").unwrap();
+        q.push(9, b"world").unwrap();
 
-                // After we've copied over all the data break
-                if data_len == 0 {
-                    return FfaError::Ok;
+        assert_eq!(q.pop(9, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(q.pop(7, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(q.pop(7, &mut buf), Err(FfaError::Retry));
+    }
+
+    #[test]
+    fn push_fails_without_partial_write() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        let mut buf = [0u8; QUEUE_BLOCK_SIZE];
+
+        for seq in 0..QUEUE_ENTRY_COUNT - 1 {
+            q.push(seq, &[seq as u8]).unwrap();
+        }
+        assert_eq!(
+            q.push(100, &[0u8; QUEUE_BLOCK_SIZE * 2]),
+            Err(FfaError::NoMemory)
+        );
+        assert_eq!(q.pop(100, &mut buf), Err(FfaError::Retry));
+
+        q.push(100, &[1u8; QUEUE_BLOCK_SIZE]).unwrap();
+        assert_eq!(q.pop(100, &mut buf), Ok(QUEUE_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn multi_block_messages_are_reassembled() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        let data: Vec<u8> = (0..QUEUE_BLOCK_SIZE * 3 + 17).map(|i| i as u8).collect();
+
+        q.push(1, b"first").unwrap();
+        q.push(2, &data).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(q.pop(1, &mut buf), Ok(5));
+
+        // Reuse the freed block so fragments are no longer in block order
+        q.push(3, b"third").unwrap();
+        assert_eq!(q.message_len(2), Ok(data.len()));
+
+        let mut out = vec![0u8; data.len() + 5];
+        assert_eq!(q.pop(2, &mut out), Ok(data.len()));
+        assert_eq!(&out[..data.len()], &data[..]);
+        assert_eq!(q.message_len(2), Err(FfaError::Retry));
+    }
+
+    #[test]
+    fn pop_keeps_message_when_buffer_too_small() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        let data = [5u8; QUEUE_BLOCK_SIZE + 1];
+
+        q.push(4, &data).unwrap();
+        let mut small = [0u8; QUEUE_BLOCK_SIZE];
+        assert_eq!(q.pop(4, &mut small), Err(FfaError::NoMemory));
+
+        let mut buf = [0u8; QUEUE_BLOCK_SIZE + 1];
+        assert_eq!(q.pop(4, &mut buf), Ok(data.len()));
+    }
+
+    #[test]
+    fn pop_checks_lengths_rewritten_by_the_peer() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        q.push(8, &[3u8; QUEUE_BLOCK_SIZE + 1]).unwrap();
+
+        // The peer grows the last fragment after the message was queued
+        let index = q.find_fragment(8, 1, QUEUE_STATE_VALID, u64::MAX).unwrap();
+        q.write_meta(index, BITMAP_LENGTH, QUEUE_BLOCK_SIZE as u16);
+
+        let mut buf = [0u8; QUEUE_BLOCK_SIZE + 1];
+        assert_eq!(q.pop(8, &mut buf), Err(FfaError::NoMemory));
+
+        // Every entry was handed back
+        assert!(q
+            .indices()
+            .all(|i| q.state(i).load(Ordering::Relaxed) != QUEUE_STATE_BUSY));
+        assert_eq!(q.message_len(8), Ok(2 * QUEUE_BLOCK_SIZE));
+
+        let mut buf = [0u8; 2 * QUEUE_BLOCK_SIZE];
+        assert_eq!(q.pop(8, &mut buf), Ok(2 * QUEUE_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn incomplete_messages_are_not_popped() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        q.push(6, &[0u8; QUEUE_BLOCK_SIZE * 2]).unwrap();
+
+        // Drop the second fragment as if the writer hadn't published it yet
+        let index = q.find_fragment(6, 1, QUEUE_STATE_VALID, u64::MAX).unwrap();
+        q.state(index).store(QUEUE_STATE_FREE, Ordering::Relaxed);
+
+        let mut buf = [0u8; QUEUE_BLOCK_SIZE * 2];
+        assert_eq!(q.pop(6, &mut buf), Err(FfaError::Retry));
+    }
+
+    #[test]
+    fn custom_geometry_is_recorded_in_header() {
+        let mut r = region();
+        let geometry = QueueGeometry::for_region(IndirectQueue::REGION_SIZE, 0xa0).unwrap();
+        assert_eq!(geometry.entry_count, 13);
+
+        let q = IndirectQueue::init_with(&mut r.0, geometry).unwrap();
+        let data: Vec<u8> = (0..0xa0 * 12 + 1).map(|i| i as u8).collect();
+        q.push(3, &data).unwrap();
+
+        let q = IndirectQueue::attach(&mut r.0).unwrap();
+        assert_eq!(q.geometry(), geometry);
+
+        let mut out = vec![0u8; data.len()];
+        assert_eq!(q.pop(3, &mut out), Ok(data.len()));
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn rejects_invalid_geometry() {
+        let mut r = region();
+        let invalid = [
+            QueueGeometry {
+                block_size: 0x100,
+                entry_count: 0,
+            },
+            QueueGeometry {
+                block_size: 0x104,
+                entry_count: 8,
+            },
+            // Header doesn't fit the first block
+            QueueGeometry {
+                block_size: 0x10,
+                entry_count: 8,
+            },
+            QueueGeometry {
+                block_size: 0x400,
+                entry_count: 65,
+            },
+            // Larger than the region
+            QueueGeometry {
+                block_size: 0x100,
+                entry_count: 9,
+            },
+        ];
+
+        for geometry in invalid {
+            assert_eq!(
+                IndirectQueue::init_with(&mut r.0, geometry).err(),
+                Some(FfaError::InvalidParameters),
+                "{geometry:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn raw_access_is_bounded_by_the_mapping() {
+        let mut r = region();
+        let base = r.0.as_mut_ptr() as u64;
+        let msg = FfaIndirectMsg::new();
+        let mut buf = [0u8; 8];
+
+        // SAFETY: the region spans REGION_SIZE + 8 bytes
+        unsafe {
+            assert_eq!(
+                msg.init_indirect_msg(base, IndirectQueue::REGION_SIZE),
+                FfaError::Ok
+            );
+            assert_eq!(msg.write_indirect_msg(base, 2, b"raw"), FfaError::Ok);
+            assert_eq!(msg.read_indirect_msg(base, 2, &mut buf), FfaError::Ok);
+            assert_eq!(&buf[..3], b"raw");
+
+            // A header claiming a larger queue than mapped
+            r.0[HEADER_COUNT..HEADER_COUNT + 2].copy_from_slice(&16u16.to_le_bytes());
+            assert_eq!(
+                msg.write_indirect_msg(base, 3, b"raw"),
+                FfaError::InvalidParameters
+            );
+            r.0[HEADER_BLOCK_SIZE..HEADER_BLOCK_SIZE + 4].copy_from_slice(&0xfff8u32.to_le_bytes());
+            assert_eq!(
+                msg.read_indirect_msg(base, 2, &mut buf),
+                FfaError::InvalidParameters
+            );
+        }
+    }
+
+    #[test]
+    fn init_indirect_msg_sizes_the_queue_from_the_length() {
+        let mut r = region();
+        let base = r.0.as_mut_ptr() as u64;
+
+        // SAFETY: the region spans REGION_SIZE + 8 bytes
+        unsafe {
+            let msg = FfaIndirectMsg::new();
+            assert_eq!(
+                msg.init_indirect_msg(base, QUEUE_BLOCK_SIZE * 4),
+                FfaError::Ok
+            );
+            assert_eq!(
+                msg.init_indirect_msg(base, QUEUE_BLOCK_SIZE),
+                FfaError::InvalidParameters
+            );
+
+            let msg = FfaIndirectMsg::with_region_len(QUEUE_BLOCK_SIZE * 4 + 0x80);
+            assert_eq!(
+                msg.init_indirect_msg(base, QUEUE_BLOCK_SIZE * 4 + 0x80),
+                FfaError::Ok
+            );
+            assert_eq!(msg.write_indirect_msg(base, 1, b"x"), FfaError::Ok);
+        }
+
+        let q = IndirectQueue::attach(&mut r.0).unwrap();
+        assert_eq!(q.geometry().entry_count, 3);
+        assert_eq!(q.message_len(1), Ok(1));
+    }
+
+    #[test]
+    fn notifier_rejects_invalid_ids() {
+        assert_eq!(
+            QueueNotifier::new(0x8001, 0x0002, 64),
+            Err(FfaError::InvalidParameters)
+        );
+    }
+
+    #[test]
+    fn push_raises_notifier() {
+        let mut r = region();
+        let notifier = QueueNotifier {
+            flags: notify::SET_PER_VCPU,
+            delay_sri: true,
+            ..QueueNotifier::new(0x8001, 0x0002, 35).unwrap()
+        };
+        let q = IndirectQueue::init(&mut r.0)
+            .unwrap()
+            .with_notifier(notifier);
+
+        let mut conduit = FakeConduit::<2>::from_responses(&[ffa_success(0)]);
+
+        q.push_with(&mut conduit, 1, b"ping").unwrap();
+        assert_eq!(
+            conduit.calls(),
+            [FfaParams {
+                x0: FfaFunctionId::FfaNotificationSet.into(),
+                x1: 0x8001_0002,
+                x2: 0b11,
+                x3: 0,
+                x4: 1 << 3,
+                ..Default::default()
+            }]
+        );
+
+        // Failing to notify leaves the message queued
+        assert_eq!(
+            q.push_with(&mut conduit, 2, b"pong"),
+            Err(FfaError::NotSupported)
+        );
+        assert_eq!(q.message_len(2), Ok(4));
+    }
+
+    /// Peer writing a message into the queue when the reader waits for it.
+    struct WriterConduit<'q, 'a> {
+        queue: &'q IndirectQueue<'a>,
+        calls: Vec<u64>,
+    }
+
+    impl Conduit for WriterConduit<'_, '_> {
+        fn call(&mut self, params: FfaParams) -> FfaParams {
+            self.calls.push(params.x0);
+
+            if params.x0 == FfaFunctionId::FfaMsgWait.into() {
+                self.queue
+                    .push_with(&mut FakeConduit::<0>::new(), 5, b"late")
+                    .ok();
+                FfaParams {
+                    x0: FfaFunctionId::FfaInterrupt.into(),
+                    ..Default::default()
                 }
+            } else {
+                ffa_success(1 << 3)
             }
         }
+    }
+
+    #[test]
+    fn recv_waits_for_notification() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0)
+            .unwrap()
+            .with_notifier(QueueNotifier::new(0x8001, 0x0002, 3).unwrap());
+        let mut buf = [0u8; 8];
+
+        let mut conduit = WriterConduit {
+            queue: &q,
+            calls: Vec::new(),
+        };
+        assert_eq!(
+            q.recv(&mut conduit, 0, 5, &mut buf),
+            Ok(RecvEvent::Message(4))
+        );
+        assert_eq!(&buf[..4], b"late");
+        assert_eq!(
+            conduit.calls,
+            [
+                FfaFunctionId::FfaMsgWait.into(),
+                u64::from(FfaFunctionId::FfaNotificationGet)
+            ]
+        );
+
+        // Available messages are returned without waiting
+        q.push_with(&mut FakeConduit::<0>::new(), 6, b"now").ok();
+        conduit.calls.clear();
+        assert_eq!(
+            q.recv(&mut conduit, 0, 6, &mut buf),
+            Ok(RecvEvent::Message(3))
+        );
+        assert!(conduit.calls.is_empty());
+    }
 
-        FfaError::NoMemory
+    #[test]
+    fn recv_hands_back_other_events() {
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0)
+            .unwrap()
+            .with_notifier(QueueNotifier::new(0x8001, 0x0002, 3).unwrap());
+        let mut buf = [0u8; 8];
+        let interrupt = FfaParams {
+            x0: FfaFunctionId::FfaInterrupt.into(),
+            x2: 42,
+            ..Default::default()
+        };
+        let request = FfaParams {
+            x0: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+            x1: 0x8001 << 16 | 0x0002,
+            x4: 0x1234,
+            ..Default::default()
+        };
+        let mut conduit = FakeConduit::<8>::from_responses(&[
+            request,
+            // Other notifications are returned, the queue's is dropped
+            interrupt,
+            ffa_success(1 << 3 | 1 << 5),
+            // An interrupt without notifications pending is returned
+            interrupt,
+            ffa_success(0),
+        ]);
+
+        assert_eq!(
+            q.recv(&mut conduit, 1, 5, &mut buf),
+            Ok(RecvEvent::Event(request))
+        );
+        assert_eq!(
+            q.recv(&mut conduit, 1, 5, &mut buf),
+            Ok(RecvEvent::Notifications(PendingNotifications {
+                sp: NotificationBitmap(1 << 5),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            q.recv(&mut conduit, 1, 5, &mut buf),
+            Ok(RecvEvent::Event(interrupt))
+        );
+        assert_eq!(conduit.calls().len(), 5);
+    }
+
+    #[test]
+    fn concurrent_producers_and_consumers() {
+        const MESSAGES: u16 = 200;
+
+        let mut r = region();
+        let q = IndirectQueue::init(&mut r.0).unwrap();
+        let len = |seq: u16| QUEUE_BLOCK_SIZE + (seq as usize % 64);
+
+        std::thread::scope(|s| {
+            for stream in 0..2u16 {
+                let q = &q;
+                let seqs = stream * MESSAGES..(stream + 1) * MESSAGES;
+
+                s.spawn({
+                    let seqs = seqs.clone();
+                    move || {
+                        for seq in seqs {
+                            while q.push(seq, &vec![seq as u8; len(seq)]) == Err(FfaError::NoMemory)
+                            {
+                                std::thread::yield_now();
+                            }
+                        }
+                    }
+                });
+
+                s.spawn(move || {
+                    let mut buf = [0u8; QUEUE_BLOCK_SIZE * 2];
+                    for seq in seqs {
+                        let n = loop {
+                            match q.pop(seq, &mut buf) {
+                                Ok(n) => break n,
+                                Err(FfaError::Retry) => std::thread::yield_now(),
+                                Err(err) => panic!("unexpected error {err:?}"),
+                            }
+                        };
+                        assert_eq!(n, len(seq));
+                        assert!(buf[..n].iter().all(|&b| b == seq as u8));
+                    }
+                });
+            }
+        });
     }
 }
@@ -0,0 +1,202 @@
+//! Virtual interrupt handling.
+//!
+//! Interrupts reach a partition either through its exception vectors, or as
+//! FFA_INTERRUPT returned by the call it was blocked in: FFA_MSG_WAIT and
+//! direct messages return a [`crate::msg::MsgEvent::Interrupt`], FFA_YIELD a
+//! [`crate::yld::YieldResult::Interrupted`]. Both paths feed the interrupt id
+//! to an [`InterruptRegistry`]. The memory, RX/TX and notification calls
+//! don't block, and fail with `Interrupted` should FFA_INTERRUPT be returned
+//! anyway.
+//!
+//! The managed exit interrupt asks an execution context to return to the
+//! normal world as soon as possible. It is recorded in a per-vCPU flag that
+//! long running request handlers poll with [`managed_exit_pending`], so they
+//! can wrap up and let the response be sent.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{FfaError, FfaFunctionId, FfaParams, Result};
+
+/// Number of execution contexts managed exits can be tracked for.
+pub const MAX_VCPUS: usize = 64;
+
+/// One bit per vCPU id
+static MANAGED_EXIT: AtomicU64 = AtomicU64::new(0);
+
+fn vcpu_bit(vcpu_id: u16) -> u64 {
+    1u64.checked_shl(vcpu_id as u32).unwrap_or(0)
+}
+
+/// Whether a managed exit was signalled to `vcpu_id` and not yet
+/// acknowledged.
+pub fn managed_exit_pending(vcpu_id: u16) -> bool {
+    MANAGED_EXIT.load(Ordering::Acquire) & vcpu_bit(vcpu_id) != 0
+}
+
+/// Records a managed exit of `vcpu_id`, for its handlers to notice with
+/// [`managed_exit_pending`].
+///
+/// Fails with `InvalidParameters` if `vcpu_id` is not below [`MAX_VCPUS`].
+pub fn signal_managed_exit(vcpu_id: u16) -> Result<()> {
+    match vcpu_bit(vcpu_id) {
+        0 => Err(FfaError::InvalidParameters),
+        bit => {
+            MANAGED_EXIT.fetch_or(bit, Ordering::Release);
+            Ok(())
+        }
+    }
+}
+
+/// Acknowledges a pending managed exit of `vcpu_id`, returning whether there
+/// was one.
+pub fn take_managed_exit(vcpu_id: u16) -> bool {
+    let bit = vcpu_bit(vcpu_id);
+    MANAGED_EXIT.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+}
+
+/// Interrupt delivered with FFA_INTERRUPT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FfaInterrupt {
+    /// Endpoint and vCPU preempted by the interrupt, when reported to the
+    /// normal world scheduler.
+    pub endpoint_id: u16,
+    pub vcpu_id: u16,
+    pub interrupt_id: u32,
+}
+
+impl TryFrom<FfaParams> for FfaInterrupt {
+    type Error = FfaError;
+
+    fn try_from(params: FfaParams) -> Result<Self> {
+        if params.x0 != FfaFunctionId::FfaInterrupt.into() {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        Ok(Self {
+            endpoint_id: (params.x1 >> 16) as u16,
+            vcpu_id: params.x1 as u16,
+            interrupt_id: params.x2 as u32,
+        })
+    }
+}
+
+/// Handler called with the id of the interrupt it was registered for.
+pub type InterruptHandler = fn(u32);
+
+/// Handlers for the virtual interrupts of a partition.
+pub struct InterruptRegistry<const N: usize> {
+    handlers: [Option<(u32, InterruptHandler)>; N],
+    managed_exit: Option<u32>,
+}
+
+impl<const N: usize> Default for InterruptRegistry<N> {
+    fn default() -> Self {
+        Self {
+            handlers: [None; N],
+            managed_exit: None,
+        }
+    }
+}
+
+impl<const N: usize> InterruptRegistry<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `handler` for interrupt `id`.
+    ///
+    /// Fails with `InvalidParameters` if `id` already has a handler, and
+    /// `NoMemory` if the registry is full.
+    pub fn register(&mut self, id: u32, handler: InterruptHandler) -> Result<()> {
+        if self.handler(id).is_some() {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let slot = self
+            .handlers
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(FfaError::NoMemory)?;
+        *slot = Some((id, handler));
+
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, id: u32) {
+        for slot in self.handlers.iter_mut() {
+            if slot.is_some_and(|(i, _)| i == id) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Sets the id of the managed exit interrupt, see
+    /// [`crate::features::FEATURE_MANAGED_EXIT`].
+    pub fn set_managed_exit(&mut self, id: Option<u32>) {
+        self.managed_exit = id;
+    }
+
+    pub fn managed_exit(&self) -> Option<u32> {
+        self.managed_exit
+    }
+
+    /// Handles interrupt `id` taken by `vcpu_id`, from the exception vectors
+    /// or FFA_INTERRUPT.
+    ///
+    /// The managed exit interrupt is recorded for `vcpu_id` before its
+    /// handler, if any, is called. Fails with `NotSupported` for interrupts
+    /// nobody handles.
+    pub fn handle(&self, vcpu_id: u16, id: u32) -> Result<()> {
+        let managed_exit = self.managed_exit == Some(id);
+        if managed_exit {
+            signal_managed_exit(vcpu_id)?;
+        }
+
+        match self.handler(id) {
+            Some(handler) => {
+                handler(id);
+                Ok(())
+            }
+            None if managed_exit => Ok(()),
+            None => Err(FfaError::NotSupported),
+        }
+    }
+
+    fn handler(&self, id: u32) -> Option<InterruptHandler> {
+        self.handlers
+            .iter()
+            .flatten()
+            .find(|(i, _)| *i == id)
+            .map(|(_, handler)| *handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignore(_id: u32) {}
+
+    #[test]
+    fn managed_exit_is_per_vcpu() {
+        let mut registry = InterruptRegistry::<1>::new();
+        registry.set_managed_exit(Some(8));
+        registry.register(9, ignore).unwrap();
+
+        registry.handle(3, 8).unwrap();
+        registry.handle(4, 9).unwrap();
+        assert!(managed_exit_pending(3));
+        assert!(!managed_exit_pending(4));
+
+        assert!(take_managed_exit(3));
+        assert!(!take_managed_exit(3));
+        assert!(!managed_exit_pending(3));
+
+        assert_eq!(registry.handle(3, 10), Err(FfaError::NotSupported));
+        assert_eq!(
+            registry.handle(MAX_VCPUS as u16, 8),
+            Err(FfaError::InvalidParameters)
+        );
+        assert!(!managed_exit_pending(MAX_VCPUS as u16));
+    }
+}
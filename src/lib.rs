@@ -3,25 +3,39 @@
 #![doc(html_root_url = "https://docs.rs/ffa/latest")]
 #![cfg_attr(not(test), no_std)]
 
+use core::time::Duration;
+
 use console::FfaConsole;
 use features::FfaFeatures;
-use msg::FfaMsg;
+use msg::{FfaMsg, MsgEvent};
+use uuid::Uuid;
 use version::FfaVersion;
 
 #[macro_use]
 pub mod console;
+#[cfg(feature = "async")]
+pub mod executor;
 pub mod features;
 pub mod indirect;
+pub mod interrupt;
 pub mod memory;
 pub mod msg;
+pub mod msg2;
 pub mod notify;
+pub mod run;
+pub mod runtime;
 pub mod rxtx;
+pub mod secondary;
+pub mod service;
+pub mod stream;
+pub mod time;
+pub mod timer;
 pub mod version;
 pub mod yld;
 
 pub type Result<T> = core::result::Result<T, FfaError>;
 
-#[derive(PartialOrd, Ord, PartialEq, Eq, Debug)]
+#[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum FfaError {
     Ok,
     NotSupported,
@@ -32,6 +46,7 @@ pub enum FfaError {
     Denied,
     Retry,
     Aborted,
+    NoData,
     UnknownError,
 }
 
@@ -47,6 +62,7 @@ impl From<FfaError> for i64 {
             FfaError::Denied => -6,
             FfaError::Retry => -7,
             FfaError::Aborted => -8,
+            FfaError::NoData => -9,
             FfaError::UnknownError => i64::MIN,
         }
     }
@@ -64,6 +80,7 @@ impl From<i64> for FfaError {
             -6 => FfaError::Denied,
             -7 => FfaError::Retry,
             -8 => FfaError::Aborted,
+            -9 => FfaError::NoData,
             _ => FfaError::UnknownError,
         }
     }
@@ -78,7 +95,7 @@ impl FfaError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FfaFunctionId {
     FfaError,
     FfaSuccess64,
@@ -95,6 +112,7 @@ pub enum FfaFunctionId {
     FfaMsgYield,
     FfaMsgRun,
     FfaMsgSend,
+    FfaMsgSend2,
     FfaMsgSendDirectReq,
     FfaMsgSendDirectResp,
     FfaMsgPoll,
@@ -107,10 +125,17 @@ pub enum FfaFunctionId {
     FfaMemReclaim,
     FfaMemFragRx,
     FfaMemFragTx,
+    FfaNotificationBitmapCreate,
+    FfaNotificationBitmapDestroy,
     FfaNotificationBind,
+    FfaNotificationUnbind,
     FfaNotificationSet,
+    FfaNotificationGet,
+    FfaNotificationInfoGet,
+    FfaSecondaryEpRegister,
     FfaMemPermGet,
     FfaMemPermSet,
+    FfaConsoleLog32,
     FfaConsoleLog,
     FfaMsgSendDirectReq2,
     FfaMsgSendDirectResp2,
@@ -134,6 +159,7 @@ impl From<FfaFunctionId> for u64 {
             FfaFunctionId::FfaMsgYield => 0x8400006c,
             FfaFunctionId::FfaMsgRun => 0x8400006d,
             FfaFunctionId::FfaMsgSend => 0x8400006e,
+            FfaFunctionId::FfaMsgSend2 => 0x84000086,
             FfaFunctionId::FfaMsgSendDirectReq => 0xc400006f,
             FfaFunctionId::FfaMsgSendDirectResp => 0xc4000070,
             FfaFunctionId::FfaMsgPoll => 0x8400006a,
@@ -146,10 +172,17 @@ impl From<FfaFunctionId> for u64 {
             FfaFunctionId::FfaMemReclaim => 0x84000077,
             FfaFunctionId::FfaMemFragRx => 0x8400007a,
             FfaFunctionId::FfaMemFragTx => 0x8400007b,
+            FfaFunctionId::FfaNotificationBitmapCreate => 0x8400007d,
+            FfaFunctionId::FfaNotificationBitmapDestroy => 0x8400007e,
             FfaFunctionId::FfaNotificationBind => 0x8400007f,
+            FfaFunctionId::FfaNotificationUnbind => 0x84000080,
             FfaFunctionId::FfaNotificationSet => 0x84000081,
+            FfaFunctionId::FfaNotificationGet => 0x84000082,
+            FfaFunctionId::FfaNotificationInfoGet => 0xc4000083,
+            FfaFunctionId::FfaSecondaryEpRegister => 0xc4000087,
             FfaFunctionId::FfaMemPermGet => 0x84000088,
             FfaFunctionId::FfaMemPermSet => 0x84000089,
+            FfaFunctionId::FfaConsoleLog32 => 0x8400008a,
             FfaFunctionId::FfaConsoleLog => 0xc400008a,
             FfaFunctionId::FfaMsgSendDirectReq2 => 0xc400008d,
             FfaFunctionId::FfaMsgSendDirectResp2 => 0xc400008e,
@@ -157,9 +190,12 @@ impl From<FfaFunctionId> for u64 {
     }
 }
 
-impl From<u64> for FfaFunctionId {
-    fn from(value: u64) -> FfaFunctionId {
-        match value {
+/// Fails with `UnknownError` for function ids this crate doesn't know.
+impl TryFrom<u64> for FfaFunctionId {
+    type Error = FfaError;
+
+    fn try_from(value: u64) -> Result<FfaFunctionId> {
+        Ok(match value {
             0x84000060 => FfaFunctionId::FfaError,
             0x84000061 => FfaFunctionId::FfaSuccess32,
             0xc4000061 => FfaFunctionId::FfaSuccess64,
@@ -175,6 +211,7 @@ impl From<u64> for FfaFunctionId {
             0x8400006c => FfaFunctionId::FfaMsgYield,
             0x8400006d => FfaFunctionId::FfaMsgRun,
             0x8400006e => FfaFunctionId::FfaMsgSend,
+            0x84000086 => FfaFunctionId::FfaMsgSend2,
             0xc400006f => FfaFunctionId::FfaMsgSendDirectReq,
             0xc4000070 => FfaFunctionId::FfaMsgSendDirectResp,
             0x8400006a => FfaFunctionId::FfaMsgPoll,
@@ -187,15 +224,22 @@ impl From<u64> for FfaFunctionId {
             0x84000077 => FfaFunctionId::FfaMemReclaim,
             0x8400007a => FfaFunctionId::FfaMemFragRx,
             0x8400007b => FfaFunctionId::FfaMemFragTx,
+            0x8400007d => FfaFunctionId::FfaNotificationBitmapCreate,
+            0x8400007e => FfaFunctionId::FfaNotificationBitmapDestroy,
             0x8400007f => FfaFunctionId::FfaNotificationBind,
+            0x84000080 => FfaFunctionId::FfaNotificationUnbind,
             0x84000081 => FfaFunctionId::FfaNotificationSet,
+            0x84000082 => FfaFunctionId::FfaNotificationGet,
+            0xc4000083 => FfaFunctionId::FfaNotificationInfoGet,
+            0xc4000087 => FfaFunctionId::FfaSecondaryEpRegister,
             0x84000088 => FfaFunctionId::FfaMemPermGet,
             0x84000089 => FfaFunctionId::FfaMemPermSet,
+            0x8400008a => FfaFunctionId::FfaConsoleLog32,
             0xc400008a => FfaFunctionId::FfaConsoleLog,
             0xc400008d => FfaFunctionId::FfaMsgSendDirectReq2,
             0xc400008e => FfaFunctionId::FfaMsgSendDirectResp2,
-            _ => panic!("Unknown FfaFunctionId value"),
-        }
+            _ => return Err(FfaError::UnknownError),
+        })
     }
 }
 
@@ -219,7 +263,18 @@ impl Ffa {
         FfaFeatures::new(id, properties).exec()
     }
 
-    pub fn msg_wait(&self) -> Result<FfaMsg> {
+    /// Id of the calling endpoint.
+    pub fn id_get(&self) -> Result<u16> {
+        let params = FfaParams {
+            x0: FfaFunctionId::FfaIdGet.into(),
+            ..Default::default()
+        };
+
+        ffa_result(ffa_smc(params)).map(|result| result.x2 as u16)
+    }
+
+    /// Waits for a direct request, or an interrupt to handle.
+    pub fn msg_wait(&self) -> Result<MsgEvent> {
         let msg = FfaMsg {
             function_id: FfaFunctionId::FfaMsgWait.into(),
             ..Default::default()
@@ -227,12 +282,105 @@ impl Ffa {
         msg.exec()
     }
 
-    pub fn msg_resp(&self, msg: &FfaMsg) -> Result<FfaMsg> {
+    /// Sends the response `msg` and waits for the next request, or an
+    /// interrupt to handle.
+    pub fn msg_resp(&self, msg: &FfaMsg) -> Result<MsgEvent> {
         msg.exec()
     }
+
+    /// Sends `msg` with FFA_MSG_SEND_DIRECT_REQ2 and returns the response, or
+    /// the interrupt that preempted the request.
+    pub fn msg_send_direct_req2(&self, msg: &FfaMsg) -> Result<MsgEvent> {
+        let req = FfaMsg {
+            function_id: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+            ..msg.clone()
+        };
+        req.exec()
+    }
+
+    /// Sends `payload` to `receiver_id` as an indirect message through the TX buffer.
+    pub fn msg_send2(
+        &self,
+        tx: &mut [u8],
+        sender_id: u16,
+        receiver_id: u16,
+        uuid: Uuid,
+        payload: &[u8],
+    ) -> Result<()> {
+        msg2::send(tx, sender_id, receiver_id, uuid, payload, 0)
+    }
+
+    pub fn rx_release(&self) -> Result<()> {
+        msg2::rx_release()
+    }
+
+    /// Gives the CPU back to the scheduler, asking to run again after
+    /// `timeout`, and tells whether it elapsed.
+    pub fn yield_for(&self, timeout: Duration) -> Result<yld::YieldResult> {
+        yld::FfaYield::with_duration(timeout).exec_with(&mut SmcConduit)
+    }
+
+    /// Gives the CPU back to the scheduler without a timeout.
+    pub fn yield_now(&self) -> Result<yld::YieldResult> {
+        yld::FfaYield::new(0).exec_with(&mut SmcConduit)
+    }
+
+    /// Runs vCPU `vcpu_id` of `endpoint_id` with FFA_RUN until it gives the
+    /// CPU back.
+    pub fn run(&self, endpoint_id: u16, vcpu_id: u16) -> Result<run::RunResult> {
+        run::run(&mut SmcConduit, endpoint_id, vcpu_id)
+    }
+
+    /// Runs vCPU `vcpu_id` of `endpoint_id` until it is idle or sends a
+    /// direct response, see [`run::run_until_idle`].
+    pub fn run_until_idle<F: FnMut(&run::RunResult)>(
+        &self,
+        endpoint_id: u16,
+        vcpu_id: u16,
+        on_event: F,
+    ) -> Result<Option<FfaMsg>> {
+        run::run_until_idle(&mut SmcConduit, endpoint_id, vcpu_id, on_event)
+    }
+
+    /// Registers `entry` as the address at which the SPMC starts secondary
+    /// execution contexts, see [`secondary`].
+    pub fn secondary_ep_register(&self, entry: u64) -> Result<()> {
+        let params = FfaParams {
+            x0: FfaFunctionId::FfaSecondaryEpRegister.into(),
+            x1: entry,
+            ..Default::default()
+        };
+
+        ffa_result(ffa_smc(params)).map(|_| ())
+    }
+
+    /// Creates the notification bitmaps of a VM with `vcpu_count` vCPUs.
+    pub fn notification_bitmap_create(&self, vm_id: u16, vcpu_count: u32) -> Result<()> {
+        notify::bitmap_create(&mut SmcConduit, vm_id, vcpu_count)
+    }
+
+    /// Endpoints and vCPUs with pending notifications, for the scheduler.
+    pub fn notification_info_get(&self) -> notify::NotificationInfoGet {
+        notify::NotificationInfoGet::new(SmcConduit)
+    }
+
+    /// Interrupt ids of the NPI and SRI.
+    pub fn notification_interrupts(&self) -> Result<notify::NotificationInterrupts> {
+        notify::NotificationInterrupts::query(&mut SmcConduit)
+    }
+
+    /// Endpoints and vCPUs to run after a Schedule Receiver Interrupt.
+    pub fn schedule_receivers<const N: usize>(&self) -> Result<notify::ScheduleList<N>> {
+        notify::schedule_receivers(SmcConduit)
+    }
+
+    /// Destroys the notification bitmaps of a VM.
+    pub fn notification_bitmap_destroy(&self, vm_id: u16) -> Result<()> {
+        notify::bitmap_destroy(&mut SmcConduit, vm_id)
+    }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct FfaParams {
     pub x0: u64,
     pub x1: u64,
@@ -254,6 +402,44 @@ pub struct FfaParams {
     pub x17: u64,
 }
 
+/// Transport used to issue FF-A calls.
+///
+/// Code that takes a conduit instead of calling [`ffa_smc`] directly can be
+/// exercised on the host with a scripted implementation.
+pub trait Conduit {
+    fn call(&mut self, params: FfaParams) -> FfaParams;
+}
+
+impl<C: Conduit + ?Sized> Conduit for &mut C {
+    fn call(&mut self, params: FfaParams) -> FfaParams {
+        (**self).call(params)
+    }
+}
+
+/// Issues FF-A calls with the SMC instruction.
+#[derive(Default)]
+pub struct SmcConduit;
+
+impl Conduit for SmcConduit {
+    fn call(&mut self, params: FfaParams) -> FfaParams {
+        ffa_smc(params)
+    }
+}
+
+/// Maps an FFA_SUCCESS return to `Ok` and an FFA_ERROR return to its status code.
+pub(crate) fn ffa_result(result: FfaParams) -> Result<FfaParams> {
+    if result.x0 == FfaFunctionId::FfaSuccess32.into()
+        || result.x0 == FfaFunctionId::FfaSuccess64.into()
+    {
+        Ok(result)
+    } else if result.x0 == FfaFunctionId::FfaError.into() {
+        // The status code is a signed 32-bit value in w2
+        Err((result.x2 as i32 as i64).into())
+    } else {
+        Err(FfaError::UnknownError)
+    }
+}
+
 /// Secure Monitor Call
 pub(crate) fn ffa_smc(params: FfaParams) -> FfaParams {
     let mut result = FfaParams::default();
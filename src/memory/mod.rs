@@ -1,4 +1,4 @@
-use super::{ffa_smc, FfaFunctionId, FfaParams, Result};
+use super::{ffa_smc, FfaError, FfaFunctionId, FfaParams, Result};
 
 impl From<&FfaMemory> for FfaParams {
     fn from(msg: &FfaMemory) -> Self {
@@ -33,12 +33,13 @@ impl FfaMemory {
         let params: FfaParams = self.into();
         let result = ffa_smc(params);
 
-        let err = result.x2 as i64;
+        let err = result.x2 as i32 as i64;
 
-        match FfaFunctionId::from(result.x0) {
-            FfaFunctionId::FfaSuccess32 | FfaFunctionId::FfaMemRetrieveResp => Ok(result),
-            FfaFunctionId::FfaError => Err(err.into()),
-            _ => panic!("Unknown error"),
+        match FfaFunctionId::try_from(result.x0) {
+            Ok(FfaFunctionId::FfaSuccess32 | FfaFunctionId::FfaMemRetrieveResp) => Ok(result),
+            Ok(FfaFunctionId::FfaError) => Err(err.into()),
+            Ok(FfaFunctionId::FfaInterrupt) => Err(FfaError::Interrupted),
+            _ => Err(FfaError::UnknownError),
         }
     }
 
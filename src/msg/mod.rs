@@ -2,14 +2,43 @@ use core::{mem, slice};
 
 use uuid::Uuid;
 
-use super::{ffa_smc, FfaError, FfaFunctionId, FfaParams, Result};
+use super::interrupt::FfaInterrupt;
+use super::{Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+pub mod payload;
+
+#[cfg(feature = "derive")]
+pub use ffa_derive::FfaPayload;
+pub use payload::{Payload, PayloadReader, PayloadWriter};
 
 impl From<&FfaMsg> for FfaParams {
     fn from(msg: &FfaMsg) -> Self {
-        let (uuid_high, uuid_low) = msg.uuid.as_u64_pair();
+        let x1 = ((msg.source_id as u64) << 16) | (msg.destination_id as u64);
+
+        if FfaMsg::is_direct_v1(msg.function_id) {
+            // No UUID, x2 holds the flags and the arguments start at x3
+            return FfaParams {
+                x0: msg.function_id,
+                x1,
+                x3: msg.args64[0],
+                x4: msg.args64[1],
+                x5: msg.args64[2],
+                x6: msg.args64[3],
+                x7: msg.args64[4],
+                ..Default::default()
+            };
+        }
+
+        // x2/x3 are reserved in FFA_MSG_SEND_DIRECT_RESP2
+        let (uuid_high, uuid_low) =
+            if msg.function_id == FfaFunctionId::FfaMsgSendDirectResp2.into() {
+                (0, 0)
+            } else {
+                msg.uuid.as_u64_pair()
+            };
         FfaParams {
             x0: msg.function_id,
-            x1: ((msg.source_id as u64) << 16) | (msg.destination_id as u64),
+            x1,
             x2: uuid_high.to_be(),
             x3: uuid_low.to_be(),
             x4: msg.args64[0],
@@ -32,20 +61,34 @@ impl From<&FfaMsg> for FfaParams {
 
 impl From<FfaParams> for FfaMsg {
     fn from(params: FfaParams) -> FfaMsg {
-        FfaMsg {
+        let mut msg = FfaMsg {
             function_id: params.x0,              // Function id is in lower 32 bits of x0
             source_id: (params.x1 >> 16) as u16, // Source in upper 16 bits
             destination_id: params.x1 as u16,    // Destination in lower 16 bits
-            uuid: Uuid::from_u64_pair(params.x2.to_be(), params.x3.to_be()),
-            args64: [
+            ..Default::default()
+        };
+
+        if FfaMsg::is_direct_v1(params.x0) {
+            msg.args64[..5]
+                .copy_from_slice(&[params.x3, params.x4, params.x5, params.x6, params.x7]);
+        } else {
+            msg.uuid = Uuid::from_u64_pair(params.x2.to_be(), params.x3.to_be());
+            msg.args64 = [
                 params.x4, params.x5, params.x6, params.x7, params.x8, params.x9, params.x10,
                 params.x11, params.x12, params.x13, params.x14, params.x15, params.x16, params.x17,
-            ],
+            ];
         }
+
+        msg
     }
 }
 
-#[derive(Default)]
+/// A direct message.
+///
+/// `args64` holds the argument registers: x4-x17 for
+/// FFA_MSG_SEND_DIRECT_REQ2/RESP2, after the UUID in x2/x3, and x3-x7 for
+/// FFA_MSG_SEND_DIRECT_REQ/RESP, which carry no UUID.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct FfaMsg {
     pub function_id: u64,
     pub source_id: u16,
@@ -55,16 +98,97 @@ pub struct FfaMsg {
 }
 
 impl FfaMsg {
+    /// Number of payload bytes carried in x4-x17.
+    pub const ARGS_LEN: usize = 112;
+
+    /// Number of payload bytes carried in x3-x7 of FFA_MSG_SEND_DIRECT_REQ.
+    pub const DIRECT_REQ_ARGS_LEN: usize = 40;
+
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn extract_u8_at_index(&self, idx: usize) -> u8 {
-        // x4-x17 is 112 bytes
-        let args: [u8; 112] = unsafe { core::mem::transmute(self.args64) };
-        args[idx]
+    /// Whether `function_id` is FFA_MSG_SEND_DIRECT_REQ/RESP, whose arguments
+    /// are in x3-x7.
+    fn is_direct_v1(function_id: u64) -> bool {
+        function_id == FfaFunctionId::FfaMsgSendDirectReq.into()
+            || function_id == FfaFunctionId::FfaMsgSendDirectResp.into()
+    }
+
+    /// Builds the direct response to `req` carrying `result`.
+    ///
+    /// The endpoint ids of the request are swapped and the response matches
+    /// the request's ABI. Responses carry no UUID. An error is reported as
+    /// its FF-A status code in the first argument register with the others
+    /// zeroed.
+    pub fn response_to(req: &FfaMsg, result: Result<FfaMsg>) -> FfaMsg {
+        let mut resp = result.unwrap_or_else(|err| {
+            let mut resp = FfaMsg::new();
+            resp.args64[0] = i64::from(err) as u64;
+            resp
+        });
+
+        resp.function_id = if req.function_id == FfaFunctionId::FfaMsgSendDirectReq2.into() {
+            FfaFunctionId::FfaMsgSendDirectResp2.into()
+        } else {
+            FfaFunctionId::FfaMsgSendDirectResp.into()
+        };
+        resp.uuid = Uuid::nil();
+        resp.source_id = req.destination_id;
+        resp.destination_id = req.source_id;
+
+        resp
+    }
+
+    /// Number of payload bytes the message carries: [`Self::ARGS_LEN`], or
+    /// [`Self::DIRECT_REQ_ARGS_LEN`] for FFA_MSG_SEND_DIRECT_REQ/RESP.
+    pub fn args_len(&self) -> usize {
+        if Self::is_direct_v1(self.function_id) {
+            Self::DIRECT_REQ_ARGS_LEN
+        } else {
+            Self::ARGS_LEN
+        }
+    }
+
+    /// Serializes `value` into the argument registers, zeroing any unused
+    /// trailing bytes. Set `function_id` first: FFA_MSG_SEND_DIRECT_REQ/RESP
+    /// only carry x3-x7.
+    ///
+    /// Fails with `InvalidParameters` if `value` doesn't fit.
+    pub fn write_args<T: Payload>(&mut self, value: &T) -> Result<()> {
+        let mut bytes = [0u8; Self::ARGS_LEN];
+        PayloadWriter::new(&mut bytes[..self.args_len()]).write(value)?;
+
+        for (arg, chunk) in self.args64.iter_mut().zip(bytes.chunks_exact(8)) {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(chunk);
+            *arg = u64::from_le_bytes(buf);
+        }
+
+        Ok(())
+    }
+
+    /// Deserializes a `T` from the argument registers.
+    pub fn read_args<T: Payload>(&self) -> Result<T> {
+        let bytes = self.args_bytes();
+        PayloadReader::new(&bytes[..self.args_len()]).read()
+    }
+
+    fn args_bytes(&self) -> [u8; Self::ARGS_LEN] {
+        let mut bytes = [0u8; Self::ARGS_LEN];
+        for (chunk, arg) in bytes.chunks_exact_mut(8).zip(self.args64.iter()) {
+            chunk.copy_from_slice(&arg.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Byte `idx` of the arguments, `None` past their end.
+    #[deprecated(note = "use `read_args` to decode typed payloads")]
+    pub fn extract_u8_at_index(&self, idx: usize) -> Option<u8> {
+        self.args_bytes().get(idx).copied()
     }
 
+    #[deprecated(note = "use `write_args` with a `Payload` type instead")]
     pub fn struct_to_args64<T>(&mut self, s: &T) {
         let size = mem::size_of::<T>();
         let args_len = self.args64.len();
@@ -84,18 +208,63 @@ impl FfaMsg {
         }
     }
 
-    pub(crate) fn exec(&self) -> Result<Self> {
-        let params: FfaParams = self.into();
-        let result = ffa_smc(params);
+    pub(crate) fn exec(&self) -> Result<MsgEvent> {
+        self.exec_with(&mut SmcConduit)
+    }
 
-        let id = FfaFunctionId::from(result.x0);
+    pub(crate) fn exec_with<C: Conduit>(&self, conduit: &mut C) -> Result<MsgEvent> {
+        let result = conduit.call(self.into());
 
-        match id {
-            FfaFunctionId::FfaMsgSendDirectReq | FfaFunctionId::FfaMsgSendDirectReq2 => {
-                Ok(result.into())
+        match FfaFunctionId::try_from(result.x0) {
+            Ok(
+                FfaFunctionId::FfaMsgSendDirectReq
+                | FfaFunctionId::FfaMsgSendDirectReq2
+                | FfaFunctionId::FfaMsgSendDirectResp
+                | FfaFunctionId::FfaMsgSendDirectResp2,
+            ) => Ok(MsgEvent::Message(result.into())),
+            Ok(FfaFunctionId::FfaError) => Err((result.x2 as i32 as i64).into()),
+            // The wait was cut short by an interrupt, see crate::interrupt
+            Ok(FfaFunctionId::FfaInterrupt) => {
+                FfaInterrupt::try_from(result).map(MsgEvent::Interrupt)
             }
-            FfaFunctionId::FfaError => Err(FfaError::InvalidParameters),
-            _ => panic!("Unknown FfaFunctionId"),
+            _ => Err(FfaError::UnknownError),
         }
     }
 }
+
+/// What a direct message call or FFA_MSG_WAIT returned with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MsgEvent {
+    Message(FfaMsg),
+    /// An interrupt for the caller to handle, e.g. with
+    /// [`crate::interrupt::InterruptRegistry::handle`], before waiting again.
+    Interrupt(FfaInterrupt),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::FakeConduit;
+
+    #[test]
+    fn interrupts_are_returned() {
+        let mut conduit = FakeConduit::<1>::from_responses(&[FfaParams {
+            x0: FfaFunctionId::FfaInterrupt.into(),
+            x2: 42,
+            ..Default::default()
+        }]);
+        let wait = FfaMsg {
+            function_id: FfaFunctionId::FfaMsgWait.into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            wait.exec_with(&mut conduit),
+            Ok(MsgEvent::Interrupt(FfaInterrupt {
+                endpoint_id: 0,
+                vcpu_id: 0,
+                interrupt_id: 42,
+            }))
+        );
+    }
+}
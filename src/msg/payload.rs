@@ -0,0 +1,265 @@
+//! Safe encoding of fixed-layout data into direct message registers.
+//!
+//! Values are serialized field by field in little-endian byte order, and the
+//! resulting byte stream is packed into the argument registers with byte 0
+//! in the least significant byte of the first one: x4 for
+//! FFA_MSG_SEND_DIRECT_REQ2, x3 for FFA_MSG_SEND_DIRECT_REQ.
+
+use uuid::Uuid;
+
+use crate::{FfaError, Result};
+
+/// A plain-data type with a fixed, explicit wire layout.
+pub trait Payload: Sized {
+    /// Number of bytes produced by [`Payload::encode`].
+    const SIZE: usize;
+
+    fn encode(&self, w: &mut PayloadWriter<'_>) -> Result<()>;
+
+    fn decode(r: &mut PayloadReader<'_>) -> Result<Self>;
+}
+
+/// Cursor writing encoded values into a byte buffer.
+pub struct PayloadWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn write<T: Payload>(&mut self, value: &T) -> Result<()> {
+        value.encode(self)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self
+            .pos
+            .checked_add(bytes.len())
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(FfaError::InvalidParameters)?;
+
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Cursor reading encoded values out of a byte buffer.
+pub struct PayloadReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn read<T: Payload>(&mut self) -> Result<T> {
+        T::decode(self)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(FfaError::InvalidParameters)?;
+
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.read_bytes(N)?);
+        Ok(out)
+    }
+}
+
+macro_rules! impl_payload_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Payload for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+
+                fn encode(&self, w: &mut PayloadWriter<'_>) -> Result<()> {
+                    w.write_bytes(&self.to_le_bytes())
+                }
+
+                fn decode(r: &mut PayloadReader<'_>) -> Result<Self> {
+                    Ok(<$ty>::from_le_bytes(r.read_array()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_payload_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Payload for () {
+    const SIZE: usize = 0;
+
+    fn encode(&self, _w: &mut PayloadWriter<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    fn decode(_r: &mut PayloadReader<'_>) -> Result<Self> {
+        Ok(())
+    }
+}
+
+impl Payload for bool {
+    const SIZE: usize = 1;
+
+    fn encode(&self, w: &mut PayloadWriter<'_>) -> Result<()> {
+        w.write(&(*self as u8))
+    }
+
+    fn decode(r: &mut PayloadReader<'_>) -> Result<Self> {
+        match r.read::<u8>()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(FfaError::InvalidParameters),
+        }
+    }
+}
+
+/// UUIDs are carried in their RFC 4122 (big-endian) byte order.
+impl Payload for Uuid {
+    const SIZE: usize = 16;
+
+    fn encode(&self, w: &mut PayloadWriter<'_>) -> Result<()> {
+        w.write_bytes(self.as_bytes())
+    }
+
+    fn decode(r: &mut PayloadReader<'_>) -> Result<Self> {
+        Ok(Uuid::from_bytes(r.read_array()?))
+    }
+}
+
+impl<T: Payload + Copy + Default, const N: usize> Payload for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn encode(&self, w: &mut PayloadWriter<'_>) -> Result<()> {
+        self.iter().try_for_each(|v| w.write(v))
+    }
+
+    fn decode(r: &mut PayloadReader<'_>) -> Result<Self> {
+        let mut out = [T::default(); N];
+        for v in out.iter_mut() {
+            *v = r.read()?;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::FfaMsg;
+
+    fn round_trip<T: Payload + PartialEq + core::fmt::Debug>(value: T) {
+        let mut buf = [0u8; 64];
+        let mut w = PayloadWriter::new(&mut buf);
+        w.write(&value).unwrap();
+        assert_eq!(w.position(), T::SIZE);
+
+        let mut r = PayloadReader::new(&buf);
+        assert_eq!(r.read::<T>().unwrap(), value);
+        assert_eq!(r.position(), T::SIZE);
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        round_trip(0xa5u8);
+        round_trip(0xbeefu16);
+        round_trip(0xdead_beefu32);
+        round_trip(u64::MAX - 1);
+        round_trip(u128::MAX / 3);
+        round_trip(-1i8);
+        round_trip(i16::MIN);
+        round_trip(-0x1234_5678i32);
+        round_trip(i64::MIN + 1);
+        round_trip(i128::MIN);
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        let mut buf = [0u8; 4];
+        PayloadWriter::new(&mut buf).write(&0x0102_0304u32).unwrap();
+        assert_eq!(buf, [4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn bool_rejects_other_bytes() {
+        round_trip(true);
+        round_trip(false);
+
+        for byte in [2u8, 0x80, 0xff] {
+            assert_eq!(
+                PayloadReader::new(&[byte]).read::<bool>(),
+                Err(FfaError::InvalidParameters)
+            );
+        }
+    }
+
+    #[test]
+    fn arrays_round_trip() {
+        round_trip([1u16, 2, 3, 0xffff]);
+        round_trip([[1u8, 2], [3, 4], [5, 6]]);
+        round_trip([true, false, true]);
+        assert_eq!(<[u32; 5]>::SIZE, 20);
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        let mut buf = [0u8; 3];
+        let mut w = PayloadWriter::new(&mut buf);
+        assert_eq!(w.write(&1u32), Err(FfaError::InvalidParameters));
+        assert_eq!(w.position(), 0);
+
+        let mut r = PayloadReader::new(&[1, 2, 3]);
+        assert_eq!(r.read::<u32>(), Err(FfaError::InvalidParameters));
+        assert_eq!(r.position(), 0);
+    }
+
+    #[test]
+    fn oversize_payloads_overflow() {
+        let mut msg = FfaMsg::new();
+        assert_eq!(msg.write_args(&[0u64; 14]), Ok(()));
+        assert_eq!(
+            msg.write_args(&[0u64; 15]),
+            Err(FfaError::InvalidParameters)
+        );
+        assert_eq!(
+            msg.read_args::<[u64; 15]>(),
+            Err(FfaError::InvalidParameters)
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn extract_u8_at_index_is_bounds_checked() {
+        let mut msg = FfaMsg::new();
+        msg.args64[13] = 0xab << 56;
+
+        assert_eq!(msg.extract_u8_at_index(FfaMsg::ARGS_LEN - 1), Some(0xab));
+        assert_eq!(msg.extract_u8_at_index(FfaMsg::ARGS_LEN), None);
+        assert_eq!(msg.extract_u8_at_index(usize::MAX), None);
+    }
+}
@@ -0,0 +1,366 @@
+//! Indirect messaging with FFA_MSG_SEND2.
+//!
+//! The sender writes a partition message header followed by the payload into
+//! its TX buffer and invokes FFA_MSG_SEND2. The SPMC copies the message into
+//! the receiver's RX buffer and signals the RX buffer full framework
+//! notification. The receiver parses the message out of its RX buffer and
+//! hands the buffer back with FFA_RX_RELEASE.
+
+use uuid::Uuid;
+
+use super::{ffa_result, Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+/// Size of the v1.2 partition message header, and offset of the payload
+/// written by [`send`].
+pub const HEADER_SIZE: usize = 0x28;
+
+/// Smallest header accepted from a sender, as laid out by FF-A v1.1.
+const HEADER_SIZE_V1_1: usize = 0x14;
+
+/// FFA_MSG_SEND2 flag asking the SPMC to delay the Schedule Receiver Interrupt
+pub const FLAG_DELAY_SRI: u32 = 1 << 1;
+
+/// Header at the start of an RX/TX buffer holding an indirect message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartitionMsgHeader {
+    pub flags: u32,
+    /// Offset of the payload from the start of the buffer.
+    pub offset: u32,
+    pub sender_id: u16,
+    pub receiver_id: u16,
+    /// Payload size in bytes.
+    pub size: u32,
+    pub uuid: Uuid,
+}
+
+impl PartitionMsgHeader {
+    pub fn new(sender_id: u16, receiver_id: u16, uuid: Uuid, size: u32) -> Self {
+        Self {
+            flags: 0,
+            offset: HEADER_SIZE as u32,
+            sender_id,
+            receiver_id,
+            size,
+            uuid,
+        }
+    }
+
+    /// Parses the header at the start of `buf`, checking that the payload it
+    /// describes lies within `buf` and that the sender isn't the receiver.
+    pub fn read(buf: &[u8]) -> Result<Self> {
+        let word = |at: usize| {
+            buf.get(at..at + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(FfaError::InvalidParameters)
+        };
+
+        let flags = word(0)?;
+        let offset = word(8)?;
+        let ids = word(12)?;
+        let size = word(16)?;
+
+        let start = offset as usize;
+        let end = start
+            .checked_add(size as usize)
+            .ok_or(FfaError::InvalidParameters)?;
+
+        if start < HEADER_SIZE_V1_1 || end > buf.len() || ids >> 16 == ids & 0xffff {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        // Senders using the v1.1 layout don't provide a UUID
+        let uuid = if start >= HEADER_SIZE {
+            let mut bytes = [0u8; 16];
+            bytes.copy_from_slice(&buf[24..40]);
+            Uuid::from_bytes(bytes)
+        } else {
+            Uuid::nil()
+        };
+
+        Ok(Self {
+            flags,
+            offset,
+            sender_id: (ids >> 16) as u16,
+            receiver_id: ids as u16,
+            size,
+            uuid,
+        })
+    }
+
+    /// Writes the header at the start of `buf`, checking that the payload it
+    /// describes lies within `buf`, after the header, and that the sender
+    /// isn't the receiver.
+    pub fn write(&self, buf: &mut [u8]) -> Result<()> {
+        let end = (self.offset as usize).checked_add(self.size as usize);
+
+        if (self.offset as usize) < HEADER_SIZE
+            || end.map_or(true, |end| end > buf.len())
+            || self.sender_id == self.receiver_id
+        {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let header = &mut buf[..HEADER_SIZE];
+
+        let ids = ((self.sender_id as u32) << 16) | self.receiver_id as u32;
+
+        header.fill(0);
+        header[0..4].copy_from_slice(&self.flags.to_le_bytes());
+        header[8..12].copy_from_slice(&self.offset.to_le_bytes());
+        header[12..16].copy_from_slice(&ids.to_le_bytes());
+        header[16..20].copy_from_slice(&self.size.to_le_bytes());
+        header[24..40].copy_from_slice(self.uuid.as_bytes());
+
+        Ok(())
+    }
+}
+
+/// Writes `payload` into the TX buffer and sends it to `receiver_id`.
+pub fn send(
+    tx: &mut [u8],
+    sender_id: u16,
+    receiver_id: u16,
+    uuid: Uuid,
+    payload: &[u8],
+    flags: u32,
+) -> Result<()> {
+    send_with(
+        &mut SmcConduit,
+        tx,
+        sender_id,
+        receiver_id,
+        uuid,
+        payload,
+        flags,
+    )
+}
+
+/// [`send`] through `conduit`.
+pub fn send_with<C: Conduit>(
+    conduit: &mut C,
+    tx: &mut [u8],
+    sender_id: u16,
+    receiver_id: u16,
+    uuid: Uuid,
+    payload: &[u8],
+    flags: u32,
+) -> Result<()> {
+    let size = u32::try_from(payload.len()).map_err(|_| FfaError::InvalidParameters)?;
+    let data = tx
+        .get_mut(HEADER_SIZE..HEADER_SIZE + payload.len())
+        .ok_or(FfaError::NoMemory)?;
+    data.copy_from_slice(payload);
+
+    PartitionMsgHeader::new(sender_id, receiver_id, uuid, size).write(tx)?;
+
+    let params = FfaParams {
+        x0: FfaFunctionId::FfaMsgSend2.into(),
+        x2: flags as u64,
+        ..Default::default()
+    };
+
+    ffa_result(conduit.call(params)).map(|_| ())
+}
+
+/// Parses the message held in the RX buffer.
+///
+/// The buffer must be released with [`rx_release`] once the caller is done
+/// with the payload.
+pub fn recv(rx: &[u8]) -> Result<(PartitionMsgHeader, &[u8])> {
+    let header = PartitionMsgHeader::read(rx)?;
+    let start = header.offset as usize;
+
+    Ok((header, &rx[start..start + header.size as usize]))
+}
+
+/// Like [`recv`], but fails with `InvalidParameters` if the message isn't
+/// addressed to `receiver_id`.
+pub fn recv_for(rx: &[u8], receiver_id: u16) -> Result<(PartitionMsgHeader, &[u8])> {
+    let (header, payload) = recv(rx)?;
+
+    if header.receiver_id != receiver_id {
+        return Err(FfaError::InvalidParameters);
+    }
+
+    Ok((header, payload))
+}
+
+/// Returns ownership of the RX buffer to the producer.
+pub fn rx_release() -> Result<()> {
+    rx_release_with(&mut SmcConduit)
+}
+
+/// [`rx_release`] through `conduit`.
+pub fn rx_release_with<C: Conduit>(conduit: &mut C) -> Result<()> {
+    let params = FfaParams {
+        x0: FfaFunctionId::FfaRxRelease.into(),
+        ..Default::default()
+    };
+
+    ffa_result(conduit.call(params)).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_success, FakeConduit};
+
+    const SENDER: u16 = 0x8001;
+    const RECEIVER: u16 = 0x8002;
+    const UUID: Uuid = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+
+    fn header_at(buf: &mut [u8], offset: u32, size: u32) {
+        let ids = ((SENDER as u32) << 16) | RECEIVER as u32;
+        buf[8..12].copy_from_slice(&offset.to_le_bytes());
+        buf[12..16].copy_from_slice(&ids.to_le_bytes());
+        buf[16..20].copy_from_slice(&size.to_le_bytes());
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let mut conduit = FakeConduit::<4>::from_responses(&[ffa_success(0), ffa_success(0)]);
+
+        let mut buf = [0xaau8; 0x100];
+        send_with(
+            &mut conduit,
+            &mut buf,
+            SENDER,
+            RECEIVER,
+            UUID,
+            b"hello",
+            FLAG_DELAY_SRI,
+        )
+        .unwrap();
+
+        let call = conduit.calls()[0];
+        assert_eq!(call.x0, FfaFunctionId::FfaMsgSend2.into());
+        assert_eq!(call.x2, FLAG_DELAY_SRI as u64);
+
+        let (header, payload) = recv_for(&buf, RECEIVER).unwrap();
+        assert_eq!(header, PartitionMsgHeader::new(SENDER, RECEIVER, UUID, 5));
+        assert_eq!(payload, b"hello");
+
+        rx_release_with(&mut conduit).unwrap();
+        assert_eq!(conduit.calls()[1].x0, FfaFunctionId::FfaRxRelease.into());
+    }
+
+    #[test]
+    fn send_reports_errors() {
+        let mut conduit = FakeConduit::<4>::new();
+        let mut buf = [0u8; 0x100];
+
+        assert_eq!(
+            send_with(&mut conduit, &mut buf, SENDER, RECEIVER, UUID, b"x", 0),
+            Err(FfaError::NotSupported)
+        );
+        assert_eq!(
+            send_with(
+                &mut conduit,
+                &mut buf,
+                SENDER,
+                RECEIVER,
+                UUID,
+                &[0; 0xe0],
+                0
+            ),
+            Err(FfaError::NoMemory)
+        );
+        assert_eq!(rx_release_with(&mut conduit), Err(FfaError::NotSupported));
+    }
+
+    #[test]
+    fn read_rejects_bad_offsets() {
+        let mut buf = [0u8; 0x40];
+
+        header_at(&mut buf, HEADER_SIZE_V1_1 as u32 - 1, 0);
+        assert_eq!(
+            PartitionMsgHeader::read(&buf),
+            Err(FfaError::InvalidParameters)
+        );
+
+        header_at(&mut buf, 0x41, 0);
+        assert_eq!(
+            PartitionMsgHeader::read(&buf),
+            Err(FfaError::InvalidParameters)
+        );
+
+        // v1.1 layout, without a UUID
+        header_at(&mut buf, HEADER_SIZE_V1_1 as u32, 4);
+        let header = PartitionMsgHeader::read(&buf).unwrap();
+        assert_eq!(header.uuid, Uuid::nil());
+        assert_eq!(header.size, 4);
+    }
+
+    #[test]
+    fn read_rejects_sizes_beyond_the_buffer() {
+        let mut buf = [0u8; 0x40];
+
+        header_at(&mut buf, HEADER_SIZE as u32, 0x19);
+        assert_eq!(
+            PartitionMsgHeader::read(&buf),
+            Err(FfaError::InvalidParameters)
+        );
+
+        header_at(&mut buf, HEADER_SIZE as u32, u32::MAX);
+        assert_eq!(
+            PartitionMsgHeader::read(&buf),
+            Err(FfaError::InvalidParameters)
+        );
+
+        header_at(&mut buf, HEADER_SIZE as u32, 0x18);
+        assert!(PartitionMsgHeader::read(&buf).is_ok());
+
+        assert_eq!(
+            PartitionMsgHeader::read(&buf[..0x10]),
+            Err(FfaError::InvalidParameters)
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_endpoints() {
+        let mut buf = [0u8; 0x40];
+        PartitionMsgHeader::new(SENDER, RECEIVER, UUID, 0)
+            .write(&mut buf)
+            .unwrap();
+
+        assert_eq!(
+            recv_for(&buf, SENDER).err(),
+            Some(FfaError::InvalidParameters)
+        );
+
+        // Messages to oneself
+        buf[12..16].copy_from_slice(&((RECEIVER as u32) << 16 | RECEIVER as u32).to_le_bytes());
+        assert_eq!(
+            PartitionMsgHeader::read(&buf),
+            Err(FfaError::InvalidParameters)
+        );
+        assert_eq!(
+            PartitionMsgHeader::new(RECEIVER, RECEIVER, UUID, 0).write(&mut buf),
+            Err(FfaError::InvalidParameters)
+        );
+    }
+
+    #[test]
+    fn write_validates_the_layout() {
+        let mut buf = [0u8; 0x40];
+        let header = PartitionMsgHeader::new(SENDER, RECEIVER, UUID, 0x18);
+        assert_eq!(header.write(&mut buf), Ok(()));
+        assert_eq!(PartitionMsgHeader::read(&buf), Ok(header));
+
+        // Payload past the end of the buffer
+        let header = PartitionMsgHeader::new(SENDER, RECEIVER, UUID, 0x19);
+        assert_eq!(header.write(&mut buf), Err(FfaError::InvalidParameters));
+
+        // Payload overlapping the header
+        let header = PartitionMsgHeader {
+            offset: HEADER_SIZE as u32 - 1,
+            ..PartitionMsgHeader::new(SENDER, RECEIVER, UUID, 0)
+        };
+        assert_eq!(header.write(&mut buf), Err(FfaError::InvalidParameters));
+
+        assert_eq!(
+            PartitionMsgHeader::new(SENDER, RECEIVER, UUID, 0).write(&mut buf[..0x20]),
+            Err(FfaError::InvalidParameters)
+        );
+    }
+}
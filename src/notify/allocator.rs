@@ -0,0 +1,358 @@
+//! Ownership of the 64 notification ids of a partition.
+//!
+//! Components sharing a partition allocate their notifications by name from
+//! a [`NotificationAllocator`] instead of picking bits by hand, and bind them
+//! through it so that the sender each id is bound to is tracked in one place.
+
+use core::fmt;
+
+use super::{NotificationBitmap, Notifications, BIND_PER_VCPU};
+use crate::{Conduit, FfaError, Result};
+
+/// Number of notification ids of an endpoint.
+pub const NOTIFICATION_COUNT: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    name: &'static str,
+    binding: Option<Binding>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Binding {
+    sender_id: u16,
+    per_vcpu: bool,
+}
+
+/// Named notification ids of a receiver, and the sender each is bound to.
+pub struct NotificationAllocator {
+    slots: [Option<Slot>; NOTIFICATION_COUNT],
+}
+
+impl Default for NotificationAllocator {
+    fn default() -> Self {
+        Self {
+            slots: [None; NOTIFICATION_COUNT],
+        }
+    }
+}
+
+impl NotificationAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocates the lowest free id to `name`.
+    ///
+    /// Fails with `InvalidParameters` if `name` is already allocated, and
+    /// `NoMemory` if all ids are taken.
+    pub fn allocate(&mut self, name: &'static str) -> Result<u8> {
+        let id = self
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FfaError::NoMemory)?;
+
+        self.allocate_id(id as u8, name)?;
+        Ok(id as u8)
+    }
+
+    /// Allocates `id` to `name`, for ids fixed by the peer's protocol.
+    ///
+    /// Fails with `Busy` if `id` belongs to another name.
+    pub fn allocate_id(&mut self, id: u8, name: &'static str) -> Result<()> {
+        if self.lookup(name).is_some() {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let slot = self
+            .slots
+            .get_mut(id as usize)
+            .ok_or(FfaError::InvalidParameters)?;
+        if slot.is_some() {
+            return Err(FfaError::Busy);
+        }
+
+        *slot = Some(Slot {
+            name,
+            binding: None,
+        });
+        Ok(())
+    }
+
+    /// Releases `id`, unbinding it first if needed.
+    pub fn free<C: Conduit>(&mut self, notifications: &mut Notifications<C>, id: u8) -> Result<()> {
+        let slot = self.slot(id)?;
+
+        if let Some(binding) = slot.binding {
+            notifications.unbind(binding.sender_id, NotificationBitmap::from_id(id)?)?;
+        }
+
+        self.slots[id as usize] = None;
+        Ok(())
+    }
+
+    /// Binds `id` to `sender_id`.
+    ///
+    /// An id already bound to another sender, or with another scope, is
+    /// unbound and bound again. Binding it again to the same sender is a
+    /// no-op.
+    pub fn bind<C: Conduit>(
+        &mut self,
+        notifications: &mut Notifications<C>,
+        id: u8,
+        sender_id: u16,
+        per_vcpu: bool,
+    ) -> Result<()> {
+        let slot = self.slot(id)?;
+        let binding = Binding {
+            sender_id,
+            per_vcpu,
+        };
+        let bitmap = NotificationBitmap::from_id(id)?;
+
+        match slot.binding {
+            Some(current) if current == binding => return Ok(()),
+            Some(current) => {
+                notifications.unbind(current.sender_id, bitmap)?;
+                self.set_binding(id, None);
+            }
+            None => {}
+        }
+
+        let flags = if per_vcpu { BIND_PER_VCPU } else { 0 };
+        notifications.bind(sender_id, flags, bitmap)?;
+        self.set_binding(id, Some(binding));
+
+        Ok(())
+    }
+
+    /// Unbinds `id`, keeping it allocated.
+    pub fn unbind<C: Conduit>(
+        &mut self,
+        notifications: &mut Notifications<C>,
+        id: u8,
+    ) -> Result<()> {
+        if let Some(binding) = self.slot(id)?.binding {
+            notifications.unbind(binding.sender_id, NotificationBitmap::from_id(id)?)?;
+            self.set_binding(id, None);
+        }
+
+        Ok(())
+    }
+
+    /// Id allocated to `name`.
+    pub fn lookup(&self, name: &str) -> Option<u8> {
+        self.slots
+            .iter()
+            .position(|slot| slot.is_some_and(|s| s.name == name))
+            .map(|id| id as u8)
+    }
+
+    /// Name `id` is allocated to.
+    pub fn name(&self, id: u8) -> Option<&'static str> {
+        self.slot(id).ok().map(|s| s.name)
+    }
+
+    /// Sender `id` is bound to.
+    pub fn sender(&self, id: u8) -> Option<u16> {
+        self.slot(id).ok()?.binding.map(|b| b.sender_id)
+    }
+
+    /// All allocated ids.
+    pub fn allocated(&self) -> NotificationBitmap {
+        self.ids().fold(NotificationBitmap::EMPTY, |bitmap, id| {
+            bitmap | Self::bit(id)
+        })
+    }
+
+    /// Ids bound to `sender_id`.
+    pub fn bound_to(&self, sender_id: u16) -> NotificationBitmap {
+        self.ids()
+            .filter(|&id| self.sender(id) == Some(sender_id))
+            .fold(NotificationBitmap::EMPTY, |bitmap, id| {
+                bitmap | Self::bit(id)
+            })
+    }
+
+    fn ids(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..NOTIFICATION_COUNT as u8).filter(|&id| self.slots[id as usize].is_some())
+    }
+
+    /// Bitmap of `id`, which ids() keeps below 64.
+    fn bit(id: u8) -> NotificationBitmap {
+        NotificationBitmap(1 << id)
+    }
+
+    fn slot(&self, id: u8) -> Result<Slot> {
+        self.slots
+            .get(id as usize)
+            .copied()
+            .flatten()
+            .ok_or(FfaError::InvalidParameters)
+    }
+
+    fn set_binding(&mut self, id: u8, binding: Option<Binding>) {
+        if let Some(slot) = &mut self.slots[id as usize] {
+            slot.binding = binding;
+        }
+    }
+}
+
+/// Dumps the allocated ids as a table, one per line.
+impl fmt::Debug for NotificationAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "id  name                      sender  scope")?;
+
+        for (id, slot) in self.slots.iter().enumerate() {
+            let Some(slot) = slot else { continue };
+
+            write!(f, "{id:<3} {:<25} ", slot.name)?;
+            match slot.binding {
+                Some(b) => writeln!(
+                    f,
+                    "{:#06x}  {}",
+                    b.sender_id,
+                    if b.per_vcpu { "per-vcpu" } else { "global" }
+                )?,
+                None => writeln!(f, "-       unbound")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_success, FakeConduit};
+    use crate::FfaFunctionId;
+
+    const RECEIVER: u16 = 0x8001;
+
+    fn conduit() -> FakeConduit<8> {
+        FakeConduit::from_responses(&[ffa_success(0); 8])
+    }
+
+    #[test]
+    fn allocates_lowest_free_id() {
+        let mut allocator = NotificationAllocator::new();
+
+        assert_eq!(allocator.allocate("a"), Ok(0));
+        assert_eq!(allocator.allocate_id(1, "fixed"), Ok(()));
+        assert_eq!(allocator.allocate("b"), Ok(2));
+        assert_eq!(allocator.lookup("fixed"), Some(1));
+        assert_eq!(allocator.name(2), Some("b"));
+        assert_eq!(allocator.allocated(), NotificationBitmap(0b111));
+
+        assert_eq!(allocator.allocate("a"), Err(FfaError::InvalidParameters));
+        assert_eq!(allocator.allocate_id(2, "c"), Err(FfaError::Busy));
+        assert_eq!(
+            allocator.allocate_id(64, "c"),
+            Err(FfaError::InvalidParameters)
+        );
+        assert_eq!(allocator.lookup("c"), None);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut allocator = NotificationAllocator::new();
+
+        for id in 0..NOTIFICATION_COUNT {
+            let name = Box::leak(format!("n{id}").into_boxed_str());
+            assert_eq!(allocator.allocate(name), Ok(id as u8));
+        }
+        assert_eq!(allocator.allocated(), NotificationBitmap(u64::MAX));
+        assert_eq!(allocator.allocate("last"), Err(FfaError::NoMemory));
+    }
+
+    #[test]
+    fn bind_and_rebind() {
+        let mut conduit = conduit();
+        let mut allocator = NotificationAllocator::new();
+        let id = allocator.allocate("queue").unwrap();
+
+        {
+            let mut notifications = Notifications::with_conduit(&mut conduit, RECEIVER);
+            allocator
+                .bind(&mut notifications, id, 0x8002, false)
+                .unwrap();
+            // Same binding, no call
+            allocator
+                .bind(&mut notifications, id, 0x8002, false)
+                .unwrap();
+            // Other sender, unbound then bound again
+            allocator
+                .bind(&mut notifications, id, 0x8003, true)
+                .unwrap();
+        }
+
+        let calls = conduit.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0].x0, FfaFunctionId::FfaNotificationBind.into());
+        assert_eq!(calls[0].x1, 0x8002_8001);
+        assert_eq!((calls[0].x2, calls[0].x3), (0, 1));
+        assert_eq!(calls[1].x0, FfaFunctionId::FfaNotificationUnbind.into());
+        assert_eq!((calls[1].x1, calls[1].x3), (0x8002_8001, 1));
+        assert_eq!(calls[2].x0, FfaFunctionId::FfaNotificationBind.into());
+        assert_eq!(calls[2].x1, 0x8003_8001);
+        assert_eq!(calls[2].x2, BIND_PER_VCPU as u64);
+
+        assert_eq!(allocator.sender(id), Some(0x8003));
+        assert_eq!(allocator.bound_to(0x8003), NotificationBitmap(1));
+        assert!(allocator.bound_to(0x8002).is_empty());
+    }
+
+    #[test]
+    fn free_unbinds() {
+        let mut conduit = conduit();
+        let mut allocator = NotificationAllocator::new();
+        allocator.allocate("a").unwrap();
+        let id = allocator.allocate("b").unwrap();
+
+        {
+            let mut notifications = Notifications::with_conduit(&mut conduit, RECEIVER);
+            allocator
+                .bind(&mut notifications, id, 0x8002, false)
+                .unwrap();
+            allocator.free(&mut notifications, id).unwrap();
+            assert_eq!(
+                allocator.free(&mut notifications, id),
+                Err(FfaError::InvalidParameters)
+            );
+            // Never bound, nothing to unbind
+            allocator.free(&mut notifications, 0).unwrap();
+        }
+
+        let calls = conduit.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].x0, FfaFunctionId::FfaNotificationUnbind.into());
+        assert_eq!(calls[1].x3, 1 << id);
+        assert!(allocator.allocated().is_empty());
+        assert_eq!(allocator.allocate("c"), Ok(0));
+    }
+
+    #[test]
+    fn debug_table() {
+        let mut conduit = conduit();
+        let mut allocator = NotificationAllocator::new();
+        allocator.allocate("rx-queue").unwrap();
+        allocator.allocate_id(5, "doorbell").unwrap();
+        allocator.allocate_id(63, "idle").unwrap();
+
+        let mut notifications = Notifications::with_conduit(&mut conduit, RECEIVER);
+        allocator
+            .bind(&mut notifications, 0, 0x8002, false)
+            .unwrap();
+        allocator.bind(&mut notifications, 5, 0x8003, true).unwrap();
+
+        assert_eq!(
+            format!("{allocator:?}"),
+            "id  name                      sender  scope\n\
+             0   rx-queue                  0x8002  global\n\
+             5   doorbell                  0x8003  per-vcpu\n\
+             63  idle                      -       unbound\n"
+        );
+    }
+}
@@ -1,4 +1,9 @@
-use super::{ffa_smc, FfaError, FfaFunctionId, FfaParams, Result};
+use super::features;
+use super::{ffa_result, ffa_smc, Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+pub mod allocator;
+
+pub use allocator::NotificationAllocator;
 
 #[derive(Default)]
 pub struct FfaNotify {
@@ -63,12 +68,789 @@ impl FfaNotify {
         let params: FfaParams = self.into();
 
         let result = ffa_smc(params);
-        let id = FfaFunctionId::from(result.x0);
+        let id = FfaFunctionId::try_from(result.x0);
 
         match id {
-            FfaFunctionId::FfaSuccess32 => Ok(result.into()),
-            FfaFunctionId::FfaError => Err(FfaError::InvalidParameters),
-            _ => panic!("Unknown FfaFunctionId"),
+            Ok(FfaFunctionId::FfaSuccess32) => Ok(result.into()),
+            Ok(FfaFunctionId::FfaError) => Err((result.x2 as i32 as i64).into()),
+            Ok(FfaFunctionId::FfaInterrupt) => Err(FfaError::Interrupted),
+            _ => Err(FfaError::UnknownError),
+        }
+    }
+}
+
+impl FfaNotify {
+    /// FFA_NOTIFICATION_SET of the notifications in `bitmap`, from
+    /// `sender_id` to `receiver_id`.
+    pub fn set(sender_id: u16, receiver_id: u16, flags: u32, bitmap: u64) -> Self {
+        let mut msg = Self {
+            function_id: FfaFunctionId::FfaNotificationSet.into(),
+            source_id: sender_id,
+            destination_id: receiver_id,
+            ..Default::default()
+        };
+        msg.args64[0] = flags as u64;
+        msg.args64[1] = bitmap & 0xffff_ffff;
+        msg.args64[2] = bitmap >> 32;
+        msg
+    }
+
+    /// Issues the call through `conduit`.
+    pub fn call<C: Conduit>(&self, conduit: &mut C) -> Result<Self> {
+        ffa_result(conduit.call(self.into())).map(Self::from)
+    }
+}
+
+/// FFA_NOTIFICATION_BIND flag binding per-vCPU notifications
+pub const BIND_PER_VCPU: u32 = 1 << 0;
+
+/// FFA_NOTIFICATION_SET flag signalling a per-vCPU notification
+pub const SET_PER_VCPU: u32 = 1 << 0;
+const SET_DELAY_SRI: u32 = 1 << 1;
+const SET_VCPU_ID_SHIFT: u32 = 16;
+
+/// FFA_NOTIFICATION_GET flags selecting the pending notifications to retrieve
+pub const GET_FROM_SP: u32 = 1 << 0;
+pub const GET_FROM_VM: u32 = 1 << 1;
+pub const GET_FROM_SPM: u32 = 1 << 2;
+pub const GET_FROM_HYPERVISOR: u32 = 1 << 3;
+
+/// Set of notification ids, one bit per id.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NotificationBitmap(pub u64);
+
+impl NotificationBitmap {
+    pub const EMPTY: Self = Self(0);
+
+    /// Bitmap holding the single notification `id`.
+    ///
+    /// Fails with `InvalidParameters` if `id` is not below 64.
+    pub const fn from_id(id: u8) -> Result<Self> {
+        if id as usize >= allocator::NOTIFICATION_COUNT {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        Ok(Self(1 << id))
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Notification ids in the bitmap, lowest first.
+    pub fn ids(self) -> impl Iterator<Item = u8> {
+        (0..64u8).filter(move |&id| self.0 & (1 << id) != 0)
+    }
+
+    fn from_regs(lo: u64, hi: u64) -> Self {
+        Self((hi << 32) | (lo & 0xffff_ffff))
+    }
+
+    fn lo(self) -> u64 {
+        self.0 & 0xffff_ffff
+    }
+
+    fn hi(self) -> u64 {
+        self.0 >> 32
+    }
+}
+
+impl core::ops::BitOr for NotificationBitmap {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for NotificationBitmap {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Notifications pending for an endpoint, as returned by FFA_NOTIFICATION_GET.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingNotifications {
+    pub sp: NotificationBitmap,
+    pub vm: NotificationBitmap,
+    /// SPM framework notifications
+    pub spm: u32,
+    /// Hypervisor framework notifications
+    pub hypervisor: u32,
+}
+
+impl From<FfaParams> for PendingNotifications {
+    fn from(params: FfaParams) -> Self {
+        Self {
+            sp: NotificationBitmap::from_regs(params.x2, params.x3),
+            vm: NotificationBitmap::from_regs(params.x4, params.x5),
+            spm: params.x6 as u32,
+            hypervisor: params.x7 as u32,
         }
     }
 }
+
+impl PendingNotifications {
+    /// SPM framework notification signalling a message in the RX buffer
+    pub const SPM_RX_BUFFER_FULL: u32 = 1 << 0;
+
+    /// Partition notifications pending, whichever the sender.
+    pub fn partition(&self) -> NotificationBitmap {
+        self.sp | self.vm
+    }
+}
+
+/// Typed FF-A notification calls made on behalf of endpoint `id`.
+///
+/// Receiver-side calls target the execution context given by
+/// [`Notifications::with_vcpu`], vCPU 0 by default.
+pub struct Notifications<C: Conduit = SmcConduit> {
+    conduit: C,
+    id: u16,
+    vcpu_id: u16,
+}
+
+impl Notifications<SmcConduit> {
+    pub fn new(id: u16) -> Self {
+        Self::with_conduit(SmcConduit, id)
+    }
+}
+
+impl<C: Conduit> Notifications<C> {
+    pub fn with_conduit(conduit: C, id: u16) -> Self {
+        Self {
+            conduit,
+            id,
+            vcpu_id: 0,
+        }
+    }
+
+    /// Makes the calls on behalf of execution context `vcpu_id`.
+    pub fn with_vcpu(mut self, vcpu_id: u16) -> Self {
+        self.vcpu_id = vcpu_id;
+        self
+    }
+
+    /// Endpoint the calls are made for.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Execution context the calls are made for.
+    pub fn vcpu_id(&self) -> u16 {
+        self.vcpu_id
+    }
+
+    pub fn conduit(&mut self) -> &mut C {
+        &mut self.conduit
+    }
+
+    /// Allows `sender_id` to signal the notifications in `bitmap` to this
+    /// endpoint.
+    pub fn bind(&mut self, sender_id: u16, flags: u32, bitmap: NotificationBitmap) -> Result<()> {
+        self.call(FfaParams {
+            x0: FfaFunctionId::FfaNotificationBind.into(),
+            x1: ((sender_id as u64) << 16) | self.id as u64,
+            x2: flags as u64,
+            x3: bitmap.lo(),
+            x4: bitmap.hi(),
+            ..Default::default()
+        })
+        .map(|_| ())
+    }
+
+    /// Binds the notifications in `bitmap` as per-vCPU notifications, which
+    /// `sender_id` signals to a given execution context of this endpoint.
+    pub fn bind_per_vcpu(&mut self, sender_id: u16, bitmap: NotificationBitmap) -> Result<()> {
+        self.bind(sender_id, BIND_PER_VCPU, bitmap)
+    }
+
+    /// Revokes the binding of the notifications in `bitmap` to `sender_id`.
+    pub fn unbind(&mut self, sender_id: u16, bitmap: NotificationBitmap) -> Result<()> {
+        self.call(FfaParams {
+            x0: FfaFunctionId::FfaNotificationUnbind.into(),
+            x1: ((sender_id as u64) << 16) | self.id as u64,
+            x3: bitmap.lo(),
+            x4: bitmap.hi(),
+            ..Default::default()
+        })
+        .map(|_| ())
+    }
+
+    /// Signals the notifications in `bitmap` to `receiver_id`, which must
+    /// have bound them to this endpoint.
+    ///
+    /// With `delay_sri`, the Schedule Receiver Interrupt is only raised once
+    /// this endpoint yields back to the normal world.
+    pub fn set(
+        &mut self,
+        receiver_id: u16,
+        flags: u32,
+        bitmap: NotificationBitmap,
+        delay_sri: bool,
+    ) -> Result<()> {
+        let flags = if delay_sri {
+            flags | SET_DELAY_SRI
+        } else {
+            flags
+        };
+
+        FfaNotify::set(self.id, receiver_id, flags, bitmap.bits())
+            .call(&mut self.conduit)
+            .map(|_| ())
+    }
+
+    /// Signals the per-vCPU notifications in `bitmap` to execution context
+    /// `vcpu_id` of `receiver_id`.
+    pub fn set_per_vcpu(
+        &mut self,
+        receiver_id: u16,
+        vcpu_id: u16,
+        bitmap: NotificationBitmap,
+        delay_sri: bool,
+    ) -> Result<()> {
+        let flags = SET_PER_VCPU | (vcpu_id as u32) << SET_VCPU_ID_SHIFT;
+        self.set(receiver_id, flags, bitmap, delay_sri)
+    }
+
+    /// Retrieves and clears the notifications pending for the current
+    /// execution context: the global notifications and those targeting its
+    /// vCPU, from partitions and the SPM.
+    pub fn drain(&mut self) -> Result<PendingNotifications> {
+        self.get(self.vcpu_id, GET_FROM_SP | GET_FROM_VM | GET_FROM_SPM)
+    }
+
+    /// Retrieves and clears the notifications pending for this endpoint on
+    /// `vcpu_id`, from the sources selected by the [`GET_FROM_SP`]-style `flags`.
+    pub fn get(&mut self, vcpu_id: u16, flags: u32) -> Result<PendingNotifications> {
+        self.call(FfaParams {
+            x0: FfaFunctionId::FfaNotificationGet.into(),
+            x1: ((vcpu_id as u64) << 16) | self.id as u64,
+            x2: flags as u64,
+            ..Default::default()
+        })
+        .map(PendingNotifications::from)
+    }
+
+    /// Endpoints and vCPUs with pending notifications, see
+    /// [`NotificationInfoGet`].
+    pub fn info_get(&mut self) -> NotificationInfoGet<&mut C> {
+        NotificationInfoGet::new(&mut self.conduit)
+    }
+
+    fn call(&mut self, params: FfaParams) -> Result<FfaParams> {
+        ffa_result(self.conduit.call(params))
+    }
+}
+
+/// Endpoint, and vCPU for per-vCPU notifications, with pending notifications.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotificationInfo {
+    pub endpoint_id: u16,
+    /// `None` when the endpoint has global notifications pending.
+    pub vcpu_id: Option<u16>,
+}
+
+/// Iterator over the pending notifications reported by
+/// FFA_NOTIFICATION_INFO_GET.
+///
+/// Each call returns lists of IDs packed four per register in x3-x17. A list
+/// starts with an endpoint id followed by up to three vCPU ids, as given by
+/// the 2-bit counts starting at bit 12 of x2. The call is repeated as long as
+/// bit 0 of x2 reports more pending notifications.
+pub struct NotificationInfoGet<C: Conduit = SmcConduit> {
+    conduit: C,
+    ids: [u16; INFO_GET_MAX_IDS],
+    sizes: u64,
+    list_count: usize,
+    /// Current list, and offset in `ids` of its endpoint id
+    list: usize,
+    list_start: usize,
+    /// vCPU of the current list to yield next
+    vcpu: usize,
+    more: bool,
+}
+
+const INFO_GET_MORE_PENDING: u64 = 1 << 0;
+const INFO_GET_LIST_COUNT_SHIFT: u64 = 7;
+const INFO_GET_LIST_COUNT_MASK: u64 = 0x1f;
+const INFO_GET_LIST_SIZES_SHIFT: u64 = 12;
+const INFO_GET_MAX_IDS: usize = 60;
+const INFO_GET_MAX_LISTS: usize = 26;
+
+impl<C: Conduit> NotificationInfoGet<C> {
+    pub fn new(conduit: C) -> Self {
+        Self {
+            conduit,
+            ids: [0; INFO_GET_MAX_IDS],
+            sizes: 0,
+            list_count: 0,
+            list: 0,
+            list_start: 0,
+            vcpu: 0,
+            // Nothing retrieved yet
+            more: true,
+        }
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        let result = ffa_result(self.conduit.call(FfaParams {
+            x0: FfaFunctionId::FfaNotificationInfoGet.into(),
+            ..Default::default()
+        }))?;
+
+        let regs = [
+            result.x3, result.x4, result.x5, result.x6, result.x7, result.x8, result.x9,
+            result.x10, result.x11, result.x12, result.x13, result.x14, result.x15, result.x16,
+            result.x17,
+        ];
+        for (i, id) in self.ids.iter_mut().enumerate() {
+            *id = (regs[i / 4] >> (16 * (i % 4))) as u16;
+        }
+
+        self.more = result.x2 & INFO_GET_MORE_PENDING != 0;
+        self.sizes = result.x2 >> INFO_GET_LIST_SIZES_SHIFT;
+        self.list_count = (((result.x2 >> INFO_GET_LIST_COUNT_SHIFT) & INFO_GET_LIST_COUNT_MASK)
+            as usize)
+            .min(INFO_GET_MAX_LISTS);
+        self.list = 0;
+        self.list_start = 0;
+        self.vcpu = 0;
+
+        Ok(())
+    }
+
+    /// Number of vCPU ids following the endpoint id of `list`.
+    fn vcpu_count(&self, list: usize) -> usize {
+        ((self.sizes >> (2 * list)) & 0x3) as usize
+    }
+}
+
+impl<C: Conduit> Iterator for NotificationInfoGet<C> {
+    type Item = Result<NotificationInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.list < self.list_count {
+                let vcpus = self.vcpu_count(self.list);
+                let (endpoint_id, vcpu_id) = match (
+                    self.ids.get(self.list_start),
+                    self.ids.get(self.list_start + 1 + self.vcpu),
+                ) {
+                    (Some(&endpoint), _) if vcpus == 0 => (endpoint, None),
+                    (Some(&endpoint), Some(&vcpu)) => (endpoint, Some(vcpu)),
+                    // Lists overflowing the registers
+                    _ => {
+                        self.list_count = 0;
+                        self.more = false;
+                        return Some(Err(FfaError::InvalidParameters));
+                    }
+                };
+
+                self.vcpu += 1;
+                if self.vcpu >= vcpus {
+                    self.list += 1;
+                    self.list_start += 1 + vcpus;
+                    self.vcpu = 0;
+                }
+
+                return Some(Ok(NotificationInfo {
+                    endpoint_id,
+                    vcpu_id,
+                }));
+            }
+
+            if !self.more {
+                return None;
+            }
+
+            match self.fetch() {
+                // More pending but nothing reported, calling again would
+                // spin forever
+                Ok(()) if self.list_count == 0 && self.more => {
+                    self.more = false;
+                    return Some(Err(FfaError::InvalidParameters));
+                }
+                Ok(()) => {}
+                Err(FfaError::NoData) => {
+                    self.more = false;
+                    return None;
+                }
+                Err(err) => {
+                    self.more = false;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Creates the bitmaps holding the notifications of VM `vm_id`, one per
+/// vCPU plus the global one, with FFA_NOTIFICATION_BITMAP_CREATE.
+///
+/// Fails with `Denied` if the bitmaps already exist, and `NoMemory` if the
+/// SPMC can't allocate them.
+pub fn bitmap_create<C: Conduit>(conduit: &mut C, vm_id: u16, vcpu_count: u32) -> Result<()> {
+    let params = FfaParams {
+        x0: FfaFunctionId::FfaNotificationBitmapCreate.into(),
+        x1: vm_id as u64,
+        x2: vcpu_count as u64,
+        ..Default::default()
+    };
+
+    ffa_result(conduit.call(params)).map(|_| ())
+}
+
+/// Destroys the notification bitmaps of VM `vm_id` with
+/// FFA_NOTIFICATION_BITMAP_DESTROY.
+///
+/// Fails with `Denied` if notifications are still pending or bound, and
+/// `InvalidParameters` if the bitmaps don't exist.
+pub fn bitmap_destroy<C: Conduit>(conduit: &mut C, vm_id: u16) -> Result<()> {
+    let params = FfaParams {
+        x0: FfaFunctionId::FfaNotificationBitmapDestroy.into(),
+        x1: vm_id as u64,
+        ..Default::default()
+    };
+
+    ffa_result(conduit.call(params)).map(|_| ())
+}
+
+/// Interrupts through which the SPMC signals notifications, as reported by
+/// FFA_FEATURES.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotificationInterrupts {
+    /// Notification Pending Interrupt, taken by a receiver with pending
+    /// notifications.
+    pub npi: Option<u32>,
+    /// Schedule Receiver Interrupt, taken by the normal world scheduler when
+    /// receivers need CPU cycles to handle their notifications.
+    pub sri: Option<u32>,
+}
+
+impl NotificationInterrupts {
+    /// Queries the interrupt ids. Interrupts not available to the caller are
+    /// left as `None`.
+    pub fn query<C: Conduit>(conduit: &mut C) -> Result<Self> {
+        Ok(Self {
+            npi: features::interrupt_id(conduit, features::FEATURE_NPI)?,
+            sri: features::interrupt_id(conduit, features::FEATURE_SRI)?,
+        })
+    }
+}
+
+/// Execution contexts to run with FFA_RUN for their pending notifications.
+pub struct ScheduleList<const N: usize> {
+    entries: [NotificationInfo; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> Default for ScheduleList<N> {
+    fn default() -> Self {
+        Self {
+            entries: [NotificationInfo {
+                endpoint_id: 0,
+                vcpu_id: None,
+            }; N],
+            len: 0,
+            truncated: false,
+        }
+    }
+}
+
+impl<const N: usize> ScheduleList<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `entry` unless already listed.
+    ///
+    /// Fails with `NoMemory` once the list is full.
+    pub fn push(&mut self, entry: NotificationInfo) -> Result<()> {
+        if self.iter().any(|e| *e == entry) {
+            return Ok(());
+        }
+
+        let slot = self.entries.get_mut(self.len).ok_or(FfaError::NoMemory)?;
+        *slot = entry;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NotificationInfo> {
+        self.entries[..self.len].iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether entries were dropped because the list was full.
+    ///
+    /// The SPMC only reports each pending notification once, so the dropped
+    /// receivers are only scheduled again when they get a new notification.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// Handles a Schedule Receiver Interrupt: retrieves the endpoints and vCPUs
+/// with pending notifications with FFA_NOTIFICATION_INFO_GET, for the caller
+/// to run them with FFA_RUN.
+///
+/// Entries without a vCPU have global notifications pending, which any of
+/// the endpoint's execution contexts can handle.
+pub fn schedule_receivers<C: Conduit, const N: usize>(conduit: C) -> Result<ScheduleList<N>> {
+    let mut list = ScheduleList::new();
+
+    for entry in NotificationInfoGet::new(conduit) {
+        if list.push(entry?).is_err() {
+            list.truncated = true;
+        }
+    }
+
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_error, FakeConduit};
+
+    /// FFA_NOTIFICATION_INFO_GET response carrying `lists`, each an endpoint
+    /// id and its vCPU ids.
+    fn info(more: bool, lists: &[(u16, &[u16])]) -> FfaParams {
+        let mut regs = [0u64; 15];
+        let mut x2 = more as u64 | (lists.len() as u64) << INFO_GET_LIST_COUNT_SHIFT;
+        let mut i = 0;
+
+        for (list, (endpoint, vcpus)) in lists.iter().enumerate() {
+            x2 |= (vcpus.len() as u64) << (INFO_GET_LIST_SIZES_SHIFT + 2 * list as u64);
+            for &id in core::iter::once(endpoint).chain(vcpus.iter()) {
+                regs[i / 4] |= (id as u64) << (16 * (i % 4));
+                i += 1;
+            }
+        }
+
+        FfaParams {
+            x0: FfaFunctionId::FfaSuccess64.into(),
+            x1: 0,
+            x2,
+            x3: regs[0],
+            x4: regs[1],
+            x5: regs[2],
+            x6: regs[3],
+            x7: regs[4],
+            x8: regs[5],
+            x9: regs[6],
+            x10: regs[7],
+            x11: regs[8],
+            x12: regs[9],
+            x13: regs[10],
+            x14: regs[11],
+            x15: regs[12],
+            x16: regs[13],
+            x17: regs[14],
+        }
+    }
+
+    fn global(endpoint_id: u16) -> NotificationInfo {
+        NotificationInfo {
+            endpoint_id,
+            vcpu_id: None,
+        }
+    }
+
+    fn vcpu(endpoint_id: u16, vcpu_id: u16) -> NotificationInfo {
+        NotificationInfo {
+            endpoint_id,
+            vcpu_id: Some(vcpu_id),
+        }
+    }
+
+    #[test]
+    fn bitmap_from_id_is_bounded() {
+        assert_eq!(NotificationBitmap::from_id(0), Ok(NotificationBitmap(1)));
+        assert_eq!(
+            NotificationBitmap::from_id(63),
+            Ok(NotificationBitmap(1 << 63))
+        );
+        assert_eq!(
+            NotificationBitmap::from_id(64),
+            Err(FfaError::InvalidParameters)
+        );
+        assert_eq!(
+            NotificationBitmap::from_id(u8::MAX),
+            Err(FfaError::InvalidParameters)
+        );
+        assert!(NotificationBitmap(1 << 63 | 1 << 5).ids().eq([5, 63]));
+    }
+
+    #[test]
+    fn info_get_decodes_vcpu_lists() {
+        let mut c = FakeConduit::<8>::from_responses(&[info(
+            false,
+            &[(0x8001, &[]), (0x8002, &[0, 3, 7]), (0x8003, &[1])],
+        )]);
+        let entries: Vec<_> = NotificationInfoGet::new(&mut c)
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            entries,
+            [
+                global(0x8001),
+                vcpu(0x8002, 0),
+                vcpu(0x8002, 3),
+                vcpu(0x8002, 7),
+                vcpu(0x8003, 1),
+            ]
+        );
+        assert_eq!(c.calls().len(), 1);
+        assert_eq!(
+            c.calls()[0].x0,
+            FfaFunctionId::FfaNotificationInfoGet.into()
+        );
+    }
+
+    #[test]
+    fn info_get_follows_pages() {
+        let mut c = FakeConduit::<8>::from_responses(&[
+            info(true, &[(0x8001, &[2]), (0x8002, &[])]),
+            info(true, &[(0x8003, &[0, 1])]),
+            info(false, &[(0x8004, &[])]),
+        ]);
+        let entries: Vec<_> = NotificationInfoGet::new(&mut c)
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            entries,
+            [
+                vcpu(0x8001, 2),
+                global(0x8002),
+                vcpu(0x8003, 0),
+                vcpu(0x8003, 1),
+                global(0x8004),
+            ]
+        );
+        assert_eq!(c.calls().len(), 3);
+    }
+
+    #[test]
+    fn info_get_fills_all_registers() {
+        // 15 lists of 3 vCPUs and 15 endpoints: 60 ids
+        let vcpus = [0u16, 1, 2];
+        let lists: Vec<(u16, &[u16])> = (0..15).map(|i| (0x8000 + i, &vcpus[..])).collect();
+        let mut c = FakeConduit::<8>::from_responses(&[info(false, &lists)]);
+
+        let entries: Vec<_> = NotificationInfoGet::new(&mut c)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries.len(), 45);
+        assert_eq!(entries[44], vcpu(0x800e, 2));
+    }
+
+    #[test]
+    fn info_get_stops_without_data() {
+        let mut c = FakeConduit::<8>::from_responses(&[ffa_error(FfaError::NoData)]);
+        assert_eq!(NotificationInfoGet::new(&mut c).count(), 0);
+
+        let mut c = FakeConduit::<8>::from_responses(&[]);
+        let mut iter = NotificationInfoGet::new(&mut c);
+        assert_eq!(iter.next(), Some(Err(FfaError::NotSupported)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn info_get_stops_on_empty_pages() {
+        let mut c = FakeConduit::<8>::from_responses(&[
+            info(true, &[(0x8001, &[])]),
+            info(true, &[]),
+            info(false, &[]),
+        ]);
+        let mut iter = NotificationInfoGet::new(&mut c);
+
+        assert_eq!(iter.next(), Some(Ok(global(0x8001))));
+        assert_eq!(iter.next(), Some(Err(FfaError::InvalidParameters)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(c.calls().len(), 2);
+    }
+
+    #[test]
+    fn info_get_rejects_lists_overflowing_the_registers() {
+        // 16 lists of 3 vCPUs need 64 ids
+        let mut params = info(false, &[]);
+        params.x2 = 16 << INFO_GET_LIST_COUNT_SHIFT | 0xffff_ffff << INFO_GET_LIST_SIZES_SHIFT;
+        let mut c = FakeConduit::<8>::from_responses(&[params]);
+
+        let results: Vec<_> = NotificationInfoGet::new(&mut c).collect();
+        assert_eq!(results.len(), 46);
+        assert_eq!(results[45], Err(FfaError::InvalidParameters));
+    }
+
+    #[test]
+    fn schedule_receivers_lists_each_context_once() {
+        // Partition 0x8002 is reported again on the second page
+        let mut c = FakeConduit::<8>::from_responses(&[
+            info(true, &[(0x8001, &[]), (0x8002, &[0, 1])]),
+            info(false, &[(0x8002, &[1, 2]), (0x8003, &[3]), (0x8001, &[])]),
+        ]);
+        let list = schedule_receivers::<_, 8>(&mut c).unwrap();
+
+        assert!(list.iter().copied().eq([
+            global(0x8001),
+            vcpu(0x8002, 0),
+            vcpu(0x8002, 1),
+            vcpu(0x8002, 2),
+            vcpu(0x8003, 3),
+        ]));
+        assert_eq!(list.len(), 5);
+        assert!(!list.truncated());
+        assert_eq!(c.calls().len(), 2);
+    }
+
+    #[test]
+    fn schedule_receivers_reports_truncation() {
+        let mut c = FakeConduit::<8>::from_responses(&[
+            info(true, &[(0x8001, &[0, 1, 2])]),
+            info(false, &[(0x8002, &[0]), (0x8003, &[])]),
+        ]);
+        let list = schedule_receivers::<_, 2>(&mut c).unwrap();
+
+        assert!(list.iter().copied().eq([vcpu(0x8001, 0), vcpu(0x8001, 1)]));
+        assert!(list.truncated());
+        // The remaining pages are still read, the SPMC only reports them once
+        assert_eq!(c.calls().len(), 2);
+    }
+
+    #[test]
+    fn schedule_receivers_propagates_errors() {
+        let mut c = FakeConduit::<8>::from_responses(&[info(true, &[(0x8001, &[])])]);
+        assert_eq!(
+            schedule_receivers::<_, 4>(&mut c).err(),
+            Some(FfaError::NotSupported)
+        );
+
+        let mut c = FakeConduit::<8>::from_responses(&[ffa_error(FfaError::NoData)]);
+        assert!(schedule_receivers::<_, 4>(&mut c).unwrap().is_empty());
+    }
+}
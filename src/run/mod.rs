@@ -0,0 +1,229 @@
+//! FFA_RUN, through which a scheduler donates CPU cycles to a vCPU of
+//! another endpoint.
+
+use super::msg::FfaMsg;
+use super::{ffa_result, Conduit, FfaError, FfaFunctionId, FfaParams, Result};
+
+/// State in which the target vCPU gave the CPU back.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunResult {
+    /// Waiting for a message or a notification, i.e. idle.
+    Waiting,
+    /// Yielded and asks to be run again, ideally once `timeout_ns` elapsed.
+    Yielded { timeout_ns: Option<u64> },
+    /// Preempted by an interrupt for the caller, and needs more cycles once
+    /// it has been handled.
+    Interrupted { interrupt_id: u32 },
+    /// Completed a direct request, with its response.
+    DirectResponse(FfaMsg),
+    /// Gave the CPU back with FFA_SUCCESS, e.g. after completing the
+    /// operation it was run for. Like [`RunResult::Waiting`], it has nothing
+    /// left to do.
+    Completed,
+}
+
+impl TryFrom<FfaParams> for RunResult {
+    type Error = FfaError;
+
+    fn try_from(params: FfaParams) -> Result<Self> {
+        let id = params.x0;
+
+        if id == FfaFunctionId::FfaMsgWait.into() {
+            Ok(Self::Waiting)
+        } else if id == FfaFunctionId::FfaSuccess32.into()
+            || id == FfaFunctionId::FfaSuccess64.into()
+        {
+            Ok(Self::Completed)
+        } else if id == FfaFunctionId::FfaMsgYield.into() {
+            let timeout_ns = ((params.x3 & 0xffff_ffff) << 32) | (params.x2 & 0xffff_ffff);
+            Ok(Self::Yielded {
+                timeout_ns: (timeout_ns != 0).then_some(timeout_ns),
+            })
+        } else if id == FfaFunctionId::FfaInterrupt.into() {
+            Ok(Self::Interrupted {
+                interrupt_id: params.x2 as u32,
+            })
+        } else if id == FfaFunctionId::FfaMsgSendDirectResp.into()
+            || id == FfaFunctionId::FfaMsgSendDirectResp2.into()
+        {
+            Ok(Self::DirectResponse(params.into()))
+        } else {
+            // FFA_ERROR carries the status, anything else is unexpected
+            Err(ffa_result(params).err().unwrap_or(FfaError::UnknownError))
+        }
+    }
+}
+
+/// Runs vCPU `vcpu_id` of `endpoint_id` until it gives the CPU back.
+///
+/// FFA_ERROR returns, e.g. `Busy` if the vCPU is running elsewhere or
+/// `Aborted` if the endpoint aborted, are mapped to `Err`.
+pub fn run<C: Conduit>(conduit: &mut C, endpoint_id: u16, vcpu_id: u16) -> Result<RunResult> {
+    let params = FfaParams {
+        x0: FfaFunctionId::FfaMsgRun.into(),
+        x1: ((endpoint_id as u64) << 16) | vcpu_id as u64,
+        ..Default::default()
+    };
+
+    RunResult::try_from(conduit.call(params))
+}
+
+/// Runs vCPU `vcpu_id` of `endpoint_id` again and again until it is idle,
+/// i.e. waiting or completed, or has answered a direct request, whose
+/// response is returned.
+///
+/// `on_event` is called with every other result before running the vCPU
+/// again, e.g. to wait for a yield timeout or to let the interrupt that
+/// preempted the vCPU be taken.
+pub fn run_until_idle<C, F>(
+    conduit: &mut C,
+    endpoint_id: u16,
+    vcpu_id: u16,
+    mut on_event: F,
+) -> Result<Option<FfaMsg>>
+where
+    C: Conduit,
+    F: FnMut(&RunResult),
+{
+    loop {
+        match run(conduit, endpoint_id, vcpu_id)? {
+            RunResult::Waiting | RunResult::Completed => return Ok(None),
+            RunResult::DirectResponse(resp) => return Ok(Some(resp)),
+            result => on_event(&result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_error, ffa_success, FakeConduit};
+
+    fn run_with(params: FfaParams) -> Result<RunResult> {
+        let mut conduit = FakeConduit::<4>::from_responses(&[params]);
+        let result = run(&mut conduit, 0x8002, 3);
+
+        let call = conduit.calls()[0];
+        assert_eq!(call.x0, FfaFunctionId::FfaMsgRun.into());
+        assert_eq!(call.x1, 0x8002_0003);
+
+        result
+    }
+
+    fn returned(id: FfaFunctionId) -> FfaParams {
+        FfaParams {
+            x0: id.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn waiting() {
+        assert_eq!(
+            run_with(returned(FfaFunctionId::FfaMsgWait)),
+            Ok(RunResult::Waiting)
+        );
+    }
+
+    #[test]
+    fn yielded() {
+        assert_eq!(
+            run_with(returned(FfaFunctionId::FfaMsgYield)),
+            Ok(RunResult::Yielded { timeout_ns: None })
+        );
+        assert_eq!(
+            run_with(FfaParams {
+                x2: 0xdead_beef,
+                x3: 0x1234,
+                ..returned(FfaFunctionId::FfaMsgYield)
+            }),
+            Ok(RunResult::Yielded {
+                timeout_ns: Some(0x1234_dead_beef)
+            })
+        );
+    }
+
+    #[test]
+    fn interrupted() {
+        assert_eq!(
+            run_with(FfaParams {
+                x2: 42,
+                ..returned(FfaFunctionId::FfaInterrupt)
+            }),
+            Ok(RunResult::Interrupted { interrupt_id: 42 })
+        );
+    }
+
+    #[test]
+    fn direct_response() {
+        let resp = FfaParams {
+            x1: 0x8002_8001,
+            x4: 7,
+            ..returned(FfaFunctionId::FfaMsgSendDirectResp2)
+        };
+        assert_eq!(
+            run_with(resp),
+            Ok(RunResult::DirectResponse(FfaMsg::from(resp)))
+        );
+    }
+
+    #[test]
+    fn completed() {
+        assert_eq!(run_with(ffa_success(0)), Ok(RunResult::Completed));
+        assert_eq!(
+            run_with(returned(FfaFunctionId::FfaSuccess64)),
+            Ok(RunResult::Completed)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(run_with(ffa_error(FfaError::Busy)), Err(FfaError::Busy));
+        assert_eq!(
+            run_with(returned(FfaFunctionId::FfaVersion)),
+            Err(FfaError::UnknownError)
+        );
+    }
+
+    #[test]
+    fn run_until_idle_reports_other_results() {
+        let mut conduit = FakeConduit::<4>::from_responses(&[
+            returned(FfaFunctionId::FfaMsgYield),
+            FfaParams {
+                x2: 5,
+                ..returned(FfaFunctionId::FfaInterrupt)
+            },
+            ffa_success(0),
+        ]);
+
+        let mut events = 0;
+        assert_eq!(
+            run_until_idle(&mut conduit, 0x8002, 0, |_| events += 1),
+            Ok(None)
+        );
+        assert_eq!(events, 2);
+        assert_eq!(conduit.calls().len(), 3);
+    }
+
+    #[test]
+    fn run_until_idle_returns_direct_responses() {
+        let resp = FfaParams {
+            x1: 0x8002_8001,
+            x4: 7,
+            ..returned(FfaFunctionId::FfaMsgSendDirectResp2)
+        };
+        let mut conduit = FakeConduit::<4>::from_responses(&[
+            returned(FfaFunctionId::FfaMsgYield),
+            resp,
+            ffa_success(0),
+        ]);
+
+        let mut events = 0;
+        assert_eq!(
+            run_until_idle(&mut conduit, 0x8002, 0, |_| events += 1),
+            Ok(Some(FfaMsg::from(resp)))
+        );
+        assert_eq!(events, 1);
+        assert_eq!(conduit.calls().len(), 2);
+    }
+}
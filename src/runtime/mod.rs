@@ -0,0 +1,743 @@
+//! Secure partition main loop.
+//!
+//! [`Partition`] owns the boilerplate shared by every partition: version
+//! negotiation, RX/TX buffer mapping and the FFA_MSG_WAIT loop. Incoming
+//! events are handed to a [`PartitionHandler`] and responses are sent back
+//! automatically.
+
+use core::time::Duration;
+
+use super::features;
+use super::interrupt::{self, FfaInterrupt};
+use super::msg::FfaMsg;
+use super::notify::{Notifications, PendingNotifications};
+use super::time::Instant;
+use super::version::FfaVersion;
+use super::yld::{FfaYield, YieldResult};
+use super::{ffa_result, Conduit, FfaError, FfaFunctionId, FfaParams, Result, SmcConduit};
+
+/// x2 flag marking a FFA_MSG_SEND_DIRECT_REQ as a framework message
+const FRAMEWORK_MSG_FLAG: u64 = 1 << 31;
+const FRAMEWORK_MSG_TYPE_MASK: u64 = 0xff;
+
+/// Framework message types, carried in bits[7:0] of x2.
+pub const FRAMEWORK_MSG_PSCI: u8 = 0x0;
+pub const FRAMEWORK_MSG_PM_RESP: u8 = 0x2;
+pub const FRAMEWORK_MSG_VM_CREATED: u8 = 0x4;
+pub const FRAMEWORK_MSG_VM_CREATED_RESP: u8 = 0x5;
+pub const FRAMEWORK_MSG_VM_DESTROYED: u8 = 0x6;
+pub const FRAMEWORK_MSG_VM_DESTROYED_RESP: u8 = 0x7;
+
+/// RX/TX buffer pair mapped during [`Partition::init`].
+#[derive(Clone, Copy, Debug)]
+pub struct RxTxConfig {
+    pub tx_addr: u64,
+    pub rx_addr: u64,
+    pub page_count: u32,
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct PartitionConfig {
+    /// Buffers to map with FFA_RXTX_MAP, if any.
+    pub rxtx: Option<RxTxConfig>,
+    /// Virtual interrupt id of the Notification Pending Interrupt, queried
+    /// with FFA_FEATURES during [`Partition::init`] if not given.
+    pub notification_interrupt: Option<u32>,
+    /// Virtual interrupt id of the managed exit interrupt, queried with
+    /// FFA_FEATURES during [`Partition::init`] if not given.
+    pub managed_exit_interrupt: Option<u32>,
+    /// Id of the execution context running this loop.
+    pub vcpu_id: u16,
+}
+
+/// A framework message delivered through FFA_MSG_SEND_DIRECT_REQ.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameworkMessage {
+    pub source_id: u16,
+    pub destination_id: u16,
+    pub kind: u8,
+    /// x3-x7 of the request
+    pub args: [u64; 5],
+}
+
+/// Callbacks invoked by [`Partition`] for each incoming event.
+///
+/// Every method has a default implementation, so handlers only implement
+/// the events they care about.
+pub trait PartitionHandler {
+    /// Handles a direct request. The returned message is sent back as the
+    /// response, see [`FfaMsg::response_to`].
+    fn direct_request(&mut self, _msg: &FfaMsg) -> Result<FfaMsg> {
+        Err(FfaError::NotSupported)
+    }
+
+    /// Handles a framework message. The result is reported to the sender as
+    /// the status of the matching response message.
+    fn framework_message(&mut self, _msg: &FrameworkMessage) -> Result<()> {
+        Ok(())
+    }
+
+    fn notification(&mut self, _pending: &PendingNotifications) {}
+
+    /// Called when the RX buffer holds an indirect message.
+    fn indirect_message(&mut self) {}
+
+    fn interrupt(&mut self, _id: u32) {}
+
+    /// Called when a managed exit is signalled while no request is in
+    /// progress. During a request, [`crate::interrupt::managed_exit_pending`]
+    /// for the partition's vCPU tells the handler to finish early.
+    fn managed_exit(&mut self) {}
+
+    /// Called before waiting for the next event. Partitions with deferred
+    /// work return the longest they can wait, to yield the CPU with
+    /// FFA_YIELD instead of waiting for a message.
+    fn idle(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// Called when the partition runs again after yielding. Events arriving
+    /// instead, e.g. interrupts, are dispatched as usual.
+    fn resumed(&mut self, _result: YieldResult) {}
+}
+
+pub struct Partition<C: Conduit = SmcConduit> {
+    conduit: C,
+    config: PartitionConfig,
+    id: u16,
+    next: FfaParams,
+}
+
+impl Partition<SmcConduit> {
+    pub fn new(config: PartitionConfig) -> Self {
+        Self::with_conduit(SmcConduit, config)
+    }
+}
+
+impl<C: Conduit> Partition<C> {
+    pub fn with_conduit(conduit: C, config: PartitionConfig) -> Self {
+        Self {
+            conduit,
+            config,
+            id: 0,
+            next: msg_wait(),
+        }
+    }
+
+    /// Negotiates the FF-A version, retrieves the partition id and the
+    /// notification and managed exit interrupts, and maps the RX/TX buffers.
+    ///
+    /// Fails with `InvalidParameters` if managed exits are in use and the
+    /// vCPU id is not below [`interrupt::MAX_VCPUS`].
+    pub fn init(&mut self) -> Result<()> {
+        let result = self.conduit.call(FfaParams {
+            x0: FfaFunctionId::FfaVersion.into(),
+            x1: (FfaVersion::FFA_VERSION_MAJOR << 16) | FfaVersion::FFA_VERSION_MINOR,
+            ..Default::default()
+        });
+
+        // Specification explicitly calls out checking bit 31
+        if result.x0 & (1 << 31) != 0 {
+            return Err((result.x0 as i32 as i64).into());
+        }
+        if result.x0 >> 16 != FfaVersion::FFA_VERSION_MAJOR {
+            return Err(FfaError::NotSupported);
+        }
+
+        let result = ffa_result(self.conduit.call(FfaParams {
+            x0: FfaFunctionId::FfaIdGet.into(),
+            ..Default::default()
+        }))?;
+        self.id = result.x2 as u16;
+
+        if self.config.notification_interrupt.is_none() {
+            self.config.notification_interrupt =
+                features::interrupt_id(&mut self.conduit, features::FEATURE_NPI)?;
+        }
+        if self.config.managed_exit_interrupt.is_none() {
+            self.config.managed_exit_interrupt =
+                features::interrupt_id(&mut self.conduit, features::FEATURE_MANAGED_EXIT)?;
+        }
+        if self.config.managed_exit_interrupt.is_some()
+            && self.config.vcpu_id as usize >= interrupt::MAX_VCPUS
+        {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        if let Some(rxtx) = self.config.rxtx {
+            ffa_result(self.conduit.call(FfaParams {
+                x0: FfaFunctionId::FfaRxTxMap.into(),
+                x1: rxtx.tx_addr,
+                x2: rxtx.rx_addr,
+                x3: rxtx.page_count as u64,
+                ..Default::default()
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    /// Partition id, as retrieved by [`Partition::init`].
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn conduit(&mut self) -> &mut C {
+        &mut self.conduit
+    }
+
+    /// Issues the pending call, either FFA_MSG_WAIT or the response to the
+    /// previous request, and dispatches the event it returns with.
+    ///
+    /// Instead of waiting, the partition yields when
+    /// [`PartitionHandler::idle`] asks for it.
+    pub fn step<H: PartitionHandler>(&mut self, handler: &mut H) -> Result<()> {
+        let call = core::mem::replace(&mut self.next, msg_wait());
+        let yielding = if call == msg_wait() {
+            handler.idle().map(FfaYield::with_duration)
+        } else {
+            None
+        };
+
+        let start = Instant::now();
+        let event = self
+            .conduit
+            .call(yielding.as_ref().map_or(call, FfaParams::from));
+
+        if let Some(yielding) = &yielding {
+            if ffa_result(event).is_ok() {
+                handler.resumed(yielding.classify(start, Instant::now()));
+                return Ok(());
+            }
+        }
+
+        self.next = self.dispatch(event, handler)?;
+        Ok(())
+    }
+
+    /// Runs the partition loop forever. Only returns on error.
+    pub fn run<H: PartitionHandler>(&mut self, handler: &mut H) -> Result<()> {
+        loop {
+            self.step(handler)?;
+        }
+    }
+
+    fn dispatch<H: PartitionHandler>(
+        &mut self,
+        event: FfaParams,
+        handler: &mut H,
+    ) -> Result<FfaParams> {
+        let id = event.x0;
+
+        if id == FfaFunctionId::FfaMsgSendDirectReq.into() && event.x2 & FRAMEWORK_MSG_FLAG != 0 {
+            let msg = FrameworkMessage {
+                source_id: (event.x1 >> 16) as u16,
+                destination_id: event.x1 as u16,
+                kind: (event.x2 & FRAMEWORK_MSG_TYPE_MASK) as u8,
+                args: [event.x3, event.x4, event.x5, event.x6, event.x7],
+            };
+            let status = handler.framework_message(&msg).err().map_or(0, i64::from);
+
+            Ok(framework_response(&msg, status))
+        } else if id == FfaFunctionId::FfaMsgSendDirectReq.into()
+            || id == FfaFunctionId::FfaMsgSendDirectReq2.into()
+        {
+            let msg = FfaMsg::from(event);
+            let resp = FfaMsg::response_to(&msg, handler.direct_request(&msg));
+
+            // Sending the response completes any managed exit signalled
+            // while the request was handled
+            interrupt::take_managed_exit(self.config.vcpu_id);
+
+            Ok((&resp).into())
+        } else if id == FfaFunctionId::FfaInterrupt.into() {
+            let irq = FfaInterrupt::try_from(event)?.interrupt_id;
+
+            if Some(irq) == self.config.notification_interrupt {
+                self.notifications(handler)?;
+            } else if Some(irq) == self.config.managed_exit_interrupt {
+                // init() checked the vCPU id is in range
+                interrupt::signal_managed_exit(self.config.vcpu_id).ok();
+                handler.managed_exit();
+                interrupt::take_managed_exit(self.config.vcpu_id);
+            } else {
+                handler.interrupt(irq);
+            }
+
+            Ok(msg_wait())
+        } else if id == FfaFunctionId::FfaError.into() {
+            Err((event.x2 as i32 as i64).into())
+        } else {
+            Err(FfaError::NotSupported)
+        }
+    }
+
+    fn notifications<H: PartitionHandler>(&mut self, handler: &mut H) -> Result<()> {
+        let pending = Notifications::with_conduit(&mut self.conduit, self.id)
+            .with_vcpu(self.config.vcpu_id)
+            .drain()?;
+
+        if pending.spm & PendingNotifications::SPM_RX_BUFFER_FULL != 0 {
+            handler.indirect_message();
+        }
+        if pending != PendingNotifications::default() {
+            handler.notification(&pending);
+        }
+
+        Ok(())
+    }
+}
+
+fn msg_wait() -> FfaParams {
+    FfaParams {
+        x0: FfaFunctionId::FfaMsgWait.into(),
+        ..Default::default()
+    }
+}
+
+fn framework_response(msg: &FrameworkMessage, status: i64) -> FfaParams {
+    let kind = match msg.kind {
+        FRAMEWORK_MSG_PSCI => FRAMEWORK_MSG_PM_RESP,
+        FRAMEWORK_MSG_VM_CREATED => FRAMEWORK_MSG_VM_CREATED_RESP,
+        FRAMEWORK_MSG_VM_DESTROYED => FRAMEWORK_MSG_VM_DESTROYED_RESP,
+        kind => kind,
+    };
+
+    FfaParams {
+        x0: FfaFunctionId::FfaMsgSendDirectResp.into(),
+        x1: ((msg.destination_id as u64) << 16) | (msg.source_id as u64),
+        x2: FRAMEWORK_MSG_FLAG | kind as u64,
+        x3: status as u64,
+        ..Default::default()
+    }
+}
+
+/// FFA_SUCCESS32 carrying `x2`, e.g. for a [`FakeConduit`] script.
+pub fn ffa_success(x2: u64) -> FfaParams {
+    FfaParams {
+        x0: FfaFunctionId::FfaSuccess32.into(),
+        x2,
+        ..Default::default()
+    }
+}
+
+/// FFA_ERROR carrying `err`, e.g. for a [`FakeConduit`] script.
+pub fn ffa_error(err: FfaError) -> FfaParams {
+    FfaParams {
+        x0: FfaFunctionId::FfaError.into(),
+        x2: i64::from(err) as u64,
+        ..Default::default()
+    }
+}
+
+/// Scripted [`Conduit`] for running partition code on the host.
+///
+/// Each call is recorded and answered with the next queued response. Once
+/// the script runs out, calls return FFA_ERROR(NOT_SUPPORTED).
+pub struct FakeConduit<const N: usize> {
+    responses: [FfaParams; N],
+    queued: usize,
+    answered: usize,
+    calls: [FfaParams; N],
+    recorded: usize,
+}
+
+impl<const N: usize> Default for FakeConduit<N> {
+    fn default() -> Self {
+        Self {
+            responses: [FfaParams::default(); N],
+            queued: 0,
+            answered: 0,
+            calls: [FfaParams::default(); N],
+            recorded: 0,
+        }
+    }
+}
+
+impl<const N: usize> FakeConduit<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Conduit answering the calls with `responses`, in order.
+    ///
+    /// Panics if there are more than `N` responses.
+    pub fn from_responses(responses: &[FfaParams]) -> Self {
+        let mut conduit = Self::new();
+        for response in responses {
+            conduit
+                .push_response(*response)
+                .expect("more responses than the conduit holds");
+        }
+        conduit
+    }
+
+    /// Queues the value returned by the next unanswered call.
+    pub fn push_response(&mut self, params: FfaParams) -> Result<()> {
+        let slot = self
+            .responses
+            .get_mut(self.queued)
+            .ok_or(FfaError::NoMemory)?;
+        *slot = params;
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Calls issued so far, oldest first.
+    pub fn calls(&self) -> &[FfaParams] {
+        &self.calls[..self.recorded]
+    }
+}
+
+impl<const N: usize> Conduit for FakeConduit<N> {
+    fn call(&mut self, params: FfaParams) -> FfaParams {
+        if let Some(slot) = self.calls.get_mut(self.recorded) {
+            *slot = params;
+            self.recorded += 1;
+        }
+
+        if self.answered < self.queued {
+            self.answered += 1;
+            self.responses[self.answered - 1]
+        } else {
+            ffa_error(FfaError::NotSupported)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::NotificationBitmap;
+
+    const SELF_ID: u16 = 0x8001;
+    const PEER_ID: u16 = 0x8003;
+
+    #[derive(Default)]
+    struct Recorder {
+        requests: usize,
+        framework: Option<FrameworkMessage>,
+        pending: Option<PendingNotifications>,
+        interrupts: usize,
+        idle: Option<Duration>,
+        resumed: Option<YieldResult>,
+        managed_exits: usize,
+        vcpu_id: u16,
+    }
+
+    impl PartitionHandler for Recorder {
+        fn direct_request(&mut self, msg: &FfaMsg) -> Result<FfaMsg> {
+            self.requests += 1;
+
+            let mut resp = FfaMsg::new();
+            resp.args64[0] = msg.args64.iter().sum();
+            Ok(resp)
+        }
+
+        fn framework_message(&mut self, msg: &FrameworkMessage) -> Result<()> {
+            self.framework = Some(*msg);
+            Err(FfaError::Denied)
+        }
+
+        fn notification(&mut self, pending: &PendingNotifications) {
+            self.pending = Some(*pending);
+        }
+
+        fn interrupt(&mut self, _id: u32) {
+            self.interrupts += 1;
+        }
+
+        fn managed_exit(&mut self) {
+            assert!(interrupt::managed_exit_pending(self.vcpu_id));
+            self.managed_exits += 1;
+        }
+
+        fn idle(&mut self) -> Option<Duration> {
+            self.idle
+        }
+
+        fn resumed(&mut self, result: YieldResult) {
+            self.resumed = Some(result);
+        }
+    }
+
+    fn partition(events: &[FfaParams]) -> Partition<FakeConduit<8>> {
+        Partition::with_conduit(
+            FakeConduit::from_responses(events),
+            PartitionConfig {
+                notification_interrupt: Some(7),
+                managed_exit_interrupt: Some(8),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn init_negotiates_and_maps_buffers() {
+        let conduit = FakeConduit::<8>::from_responses(&[
+            FfaParams {
+                x0: 0x1_0002,
+                ..Default::default()
+            },
+            ffa_success(SELF_ID as u64),
+            ffa_success(5),
+            ffa_error(FfaError::NotSupported),
+            ffa_success(0),
+        ]);
+
+        let mut p = Partition::with_conduit(
+            conduit,
+            PartitionConfig {
+                rxtx: Some(RxTxConfig {
+                    tx_addr: 0x1000,
+                    rx_addr: 0x2000,
+                    page_count: 1,
+                }),
+                ..Default::default()
+            },
+        );
+        p.init().unwrap();
+
+        assert_eq!(p.id(), SELF_ID);
+        assert_eq!(p.config.notification_interrupt, Some(5));
+        assert_eq!(p.config.managed_exit_interrupt, None);
+
+        let calls = p.conduit().calls();
+        let ids: [u64; 5] = core::array::from_fn(|i| calls[i].x0);
+        assert_eq!(
+            ids,
+            [
+                FfaFunctionId::FfaVersion.into(),
+                FfaFunctionId::FfaIdGet.into(),
+                FfaFunctionId::FfaFeatures.into(),
+                FfaFunctionId::FfaFeatures.into(),
+                FfaFunctionId::FfaRxTxMap.into(),
+            ]
+        );
+        assert_eq!(calls[2].x1, features::FEATURE_NPI);
+        assert_eq!(calls[3].x1, features::FEATURE_MANAGED_EXIT);
+        assert_eq!((calls[4].x1, calls[4].x2, calls[4].x3), (0x1000, 0x2000, 1));
+    }
+
+    #[test]
+    fn init_rejects_other_major_versions() {
+        let mut p = partition(&[FfaParams {
+            x0: 0x2_0000,
+            ..Default::default()
+        }]);
+        assert_eq!(p.init(), Err(FfaError::NotSupported));
+
+        // FFA_VERSION reports errors in w0
+        let mut p = partition(&[FfaParams {
+            x0: i64::from(FfaError::NotSupported) as u32 as u64,
+            ..Default::default()
+        }]);
+        assert_eq!(p.init(), Err(FfaError::NotSupported));
+    }
+
+    #[test]
+    fn direct_request_is_answered() {
+        let mut p = partition(&[FfaParams {
+            x0: FfaFunctionId::FfaMsgSendDirectReq.into(),
+            x1: ((PEER_ID as u64) << 16) | SELF_ID as u64,
+            x3: 1,
+            x4: 2,
+            x5: 3,
+            x6: 4,
+            x7: 5,
+            ..Default::default()
+        }]);
+        let mut handler = Recorder::default();
+
+        p.step(&mut handler).unwrap();
+        assert_eq!(handler.requests, 1);
+
+        // The response goes out with the next call
+        assert_eq!(p.step(&mut handler), Err(FfaError::NotSupported));
+        let resp = p.conduit().calls()[1];
+        assert_eq!(resp.x0, FfaFunctionId::FfaMsgSendDirectResp.into());
+        assert_eq!(resp.x1, ((SELF_ID as u64) << 16) | PEER_ID as u64);
+        assert_eq!((resp.x2, resp.x3, resp.x4), (0, 15, 0));
+    }
+
+    #[test]
+    fn direct_request2_is_answered() {
+        let mut p = partition(&[FfaParams {
+            x0: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+            x1: ((PEER_ID as u64) << 16) | SELF_ID as u64,
+            x2: 0x1111,
+            x3: 0x2222,
+            x4: 10,
+            x17: 20,
+            ..Default::default()
+        }]);
+        let mut handler = Recorder::default();
+
+        p.step(&mut handler).unwrap();
+        let _ = p.step(&mut handler);
+
+        let resp = p.conduit().calls()[1];
+        assert_eq!(resp.x0, FfaFunctionId::FfaMsgSendDirectResp2.into());
+        assert_eq!(resp.x1, ((SELF_ID as u64) << 16) | PEER_ID as u64);
+        assert_eq!(resp.x4, 30);
+    }
+
+    #[test]
+    fn framework_message_reports_status() {
+        let mut p = partition(&[FfaParams {
+            x0: FfaFunctionId::FfaMsgSendDirectReq.into(),
+            x1: ((PEER_ID as u64) << 16) | SELF_ID as u64,
+            x2: FRAMEWORK_MSG_FLAG | FRAMEWORK_MSG_VM_CREATED as u64,
+            x3: 0xaa,
+            x7: 0xee,
+            ..Default::default()
+        }]);
+        let mut handler = Recorder::default();
+
+        p.step(&mut handler).unwrap();
+        let msg = handler.framework.unwrap();
+        assert_eq!(msg.kind, FRAMEWORK_MSG_VM_CREATED);
+        assert_eq!((msg.source_id, msg.destination_id), (PEER_ID, SELF_ID));
+        assert_eq!(msg.args, [0xaa, 0, 0, 0, 0xee]);
+        assert_eq!(handler.requests, 0);
+
+        let _ = p.step(&mut handler);
+        let resp = p.conduit().calls()[1];
+        assert_eq!(resp.x0, FfaFunctionId::FfaMsgSendDirectResp.into());
+        assert_eq!(resp.x1, ((SELF_ID as u64) << 16) | PEER_ID as u64);
+        assert_eq!(
+            resp.x2,
+            FRAMEWORK_MSG_FLAG | FRAMEWORK_MSG_VM_CREATED_RESP as u64
+        );
+        assert_eq!(resp.x3, i64::from(FfaError::Denied) as u64);
+    }
+
+    #[test]
+    fn notification_interrupt_drains_notifications() {
+        let mut p = partition(&[
+            FfaParams {
+                x0: FfaFunctionId::FfaInterrupt.into(),
+                x2: 7,
+                ..Default::default()
+            },
+            FfaParams {
+                x5: 1,
+                x6: PendingNotifications::SPM_RX_BUFFER_FULL as u64,
+                ..ffa_success(1 << 3)
+            },
+        ]);
+        let mut handler = Recorder::default();
+
+        p.step(&mut handler).unwrap();
+
+        let get = p.conduit().calls()[1];
+        assert_eq!(get.x0, FfaFunctionId::FfaNotificationGet.into());
+        assert_eq!(get.x1 as u16, p.id());
+
+        let pending = handler.pending.unwrap();
+        assert_eq!(pending.sp, NotificationBitmap::from_id(3).unwrap());
+        assert_eq!(pending.vm, NotificationBitmap::from_id(32).unwrap());
+        assert_eq!(pending.spm, PendingNotifications::SPM_RX_BUFFER_FULL);
+        assert_eq!(handler.interrupts, 0);
+
+        // Back to waiting
+        let _ = p.step(&mut handler);
+        assert_eq!(p.conduit().calls()[2], msg_wait());
+    }
+
+    #[test]
+    fn other_interrupts_reach_the_handler() {
+        let mut p = partition(&[FfaParams {
+            x0: FfaFunctionId::FfaInterrupt.into(),
+            x2: 42,
+            ..Default::default()
+        }]);
+        let mut handler = Recorder::default();
+
+        p.step(&mut handler).unwrap();
+        assert_eq!(handler.interrupts, 1);
+        assert!(handler.pending.is_none());
+    }
+
+    #[test]
+    fn errors_are_propagated() {
+        let mut p = partition(&[ffa_error(FfaError::Busy)]);
+        let mut handler = Recorder::default();
+
+        assert_eq!(p.step(&mut handler), Err(FfaError::Busy));
+        assert_eq!(p.conduit().calls(), &[msg_wait()]);
+    }
+
+    #[test]
+    fn zero_idle_timeout_still_yields_with_a_timeout() {
+        let mut p = partition(&[ffa_success(0)]);
+        let mut handler = Recorder {
+            idle: Some(Duration::ZERO),
+            ..Default::default()
+        };
+
+        p.step(&mut handler).unwrap();
+
+        let call = p.conduit().calls()[0];
+        assert_eq!(call.x0, FfaFunctionId::FfaMsgYield.into());
+        assert_eq!((call.x2, call.x3), (1, 0));
+        assert!(handler.resumed.is_some());
+    }
+
+    #[test]
+    fn managed_exit_is_signalled_to_the_handler() {
+        // vCPU 11 isn't used by other tests sharing the managed exit flags
+        let mut p = Partition::with_conduit(
+            FakeConduit::<8>::from_responses(&[FfaParams {
+                x0: FfaFunctionId::FfaInterrupt.into(),
+                x2: 8,
+                ..Default::default()
+            }]),
+            PartitionConfig {
+                vcpu_id: 11,
+                managed_exit_interrupt: Some(8),
+                ..Default::default()
+            },
+        );
+        let mut handler = Recorder {
+            vcpu_id: 11,
+            ..Default::default()
+        };
+
+        p.step(&mut handler).unwrap();
+
+        assert_eq!(handler.managed_exits, 1);
+        assert_eq!(handler.interrupts, 0);
+        assert!(!interrupt::managed_exit_pending(11));
+    }
+
+    #[test]
+    fn init_checks_the_vcpu_id_for_managed_exits() {
+        let responses = [
+            FfaParams {
+                x0: 0x1_0002,
+                ..Default::default()
+            },
+            ffa_success(SELF_ID as u64),
+            ffa_success(5),
+            ffa_success(8),
+        ];
+        let config = PartitionConfig {
+            vcpu_id: interrupt::MAX_VCPUS as u16,
+            ..Default::default()
+        };
+
+        let mut p = Partition::with_conduit(FakeConduit::<8>::from_responses(&responses), config);
+        assert_eq!(p.init(), Err(FfaError::InvalidParameters));
+
+        // Without managed exits, any vCPU id goes
+        let responses = [
+            responses[0],
+            responses[1],
+            responses[2],
+            ffa_error(FfaError::NotSupported),
+        ];
+        let mut p = Partition::with_conduit(FakeConduit::<8>::from_responses(&responses), config);
+        assert_eq!(p.init(), Ok(()));
+    }
+}
@@ -30,12 +30,13 @@ impl FfaRxTxMsg {
 
         let result = ffa_smc(params);
 
-        let err = result.x2 as i64;
+        let err = result.x2 as i32 as i64;
 
-        match FfaFunctionId::from(result.x0) {
-            FfaFunctionId::FfaSuccess32 => FfaError::Ok,
-            FfaFunctionId::FfaError => err.into(),
-            _ => panic!("Unknown error"),
+        match FfaFunctionId::try_from(result.x0) {
+            Ok(FfaFunctionId::FfaSuccess32) => FfaError::Ok,
+            Ok(FfaFunctionId::FfaError) => err.into(),
+            Ok(FfaFunctionId::FfaInterrupt) => FfaError::Interrupted,
+            _ => FfaError::UnknownError,
         }
     }
 
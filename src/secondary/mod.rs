@@ -0,0 +1,252 @@
+//! Secondary execution context bring-up.
+//!
+//! A multi-core partition registers a secondary entry point with
+//! FFA_SECONDARY_EP_REGISTER, at which the SPMC starts each of its other
+//! execution contexts with the vCPU id in x0. The entry point provided here
+//! switches to the stack reserved for that vCPU and calls the partition's
+//! [`SecondaryMain`] with an [`ExecutionContext`], which typically enters the
+//! partition runtime loop with [`ExecutionContext::run`].
+//!
+//! Anything the architecture requires per core, such as enabling the MMU
+//! with the primary core's translation tables, is left to [`SecondaryMain`].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::runtime::{Partition, PartitionConfig, PartitionHandler};
+use super::{Ffa, FfaError, Result};
+
+/// Function run by each secondary execution context.
+pub type SecondaryMain = fn(ExecutionContext) -> !;
+
+/// Alignment required for the stacks handed to [`install`].
+pub const STACK_ALIGN: usize = 16;
+
+// Read by ffa_secondary_entry before it has a stack, hence plain words
+static STACK_BASE: AtomicUsize = AtomicUsize::new(0);
+static STACK_SIZE: AtomicUsize = AtomicUsize::new(0);
+static STACK_COUNT: AtomicUsize = AtomicUsize::new(0);
+static MAIN: AtomicUsize = AtomicUsize::new(0);
+
+/// The execution context, i.e. vCPU, code is running on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecutionContext {
+    vcpu_id: u16,
+}
+
+impl ExecutionContext {
+    /// Context for `vcpu_id`, e.g. vCPU 0 for the primary core.
+    pub fn new(vcpu_id: u16) -> Self {
+        Self { vcpu_id }
+    }
+
+    pub fn vcpu_id(&self) -> u16 {
+        self.vcpu_id
+    }
+
+    /// Runs the partition loop on this execution context. Only returns on
+    /// error.
+    ///
+    /// The RX/TX buffers are shared by all execution contexts, so the ones in
+    /// `config` are only mapped by vCPU 0.
+    pub fn run<H: PartitionHandler>(&self, config: PartitionConfig, handler: &mut H) -> Result<()> {
+        let config = PartitionConfig {
+            vcpu_id: self.vcpu_id,
+            rxtx: config.rxtx.filter(|_| self.vcpu_id == 0),
+            ..config
+        };
+
+        let mut partition = Partition::new(config);
+        partition.init()?;
+        partition.run(handler)
+    }
+}
+
+/// Sets up the per-vCPU stacks and the function secondary execution
+/// contexts run.
+///
+/// `stacks` is split into `stack_size` stacks, one per vCPU id. Execution
+/// contexts without a stack are parked.
+///
+/// Secondary execution contexts read these settings before their MMU and
+/// caches are enabled, so they are cleaned to the point of coherency.
+pub fn install(stacks: &'static mut [u8], stack_size: usize, main: SecondaryMain) -> Result<()> {
+    if stack_size == 0
+        || stack_size % STACK_ALIGN != 0
+        || stacks.as_ptr() as usize % STACK_ALIGN != 0
+    {
+        return Err(FfaError::InvalidParameters);
+    }
+
+    STACK_BASE.store(stacks.as_mut_ptr() as usize, Ordering::Relaxed);
+    STACK_SIZE.store(stack_size, Ordering::Relaxed);
+    MAIN.store(main as usize, Ordering::Relaxed);
+    STACK_COUNT.store(stacks.len() / stack_size, Ordering::Release);
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        for word in [&STACK_BASE, &STACK_SIZE, &STACK_COUNT, &MAIN] {
+            // SAFETY: cleaning a line to PoC leaves its contents unchanged
+            unsafe {
+                core::arch::asm!("dc cvac, {}", in(reg) word.as_ptr(), options(nostack));
+            }
+        }
+        // SAFETY: waits for the cleans to complete, no other effect
+        unsafe {
+            core::arch::asm!("dsb sy", options(nostack));
+        }
+    }
+
+    Ok(())
+}
+
+/// vCPU the caller runs on, told from its stack: secondary execution
+/// contexts run on the stack [`install`] reserved for them, anything else is
+/// taken to be vCPU 0.
+pub fn current_vcpu_id() -> u16 {
+    let marker = 0u8;
+    vcpu_on_stack(core::ptr::addr_of!(marker) as usize)
+}
+
+/// vCPU whose stack holds address `sp`, 0 if none does.
+fn vcpu_on_stack(sp: usize) -> u16 {
+    let count = STACK_COUNT.load(Ordering::Acquire);
+    let base = STACK_BASE.load(Ordering::Relaxed);
+    let size = STACK_SIZE.load(Ordering::Relaxed);
+
+    match sp.checked_sub(base).map(|offset| offset / size.max(1)) {
+        Some(index) if index < count => index as u16,
+        _ => 0,
+    }
+}
+
+/// Address of the secondary entry point, on targets providing one.
+pub fn entry_point() -> Option<u64> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        extern "C" {
+            fn ffa_secondary_entry();
+        }
+        Some(ffa_secondary_entry as *const () as usize as u64)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    None
+}
+
+/// Installs the stacks and `main`, then registers the secondary entry point
+/// with the SPMC.
+pub fn register(stacks: &'static mut [u8], stack_size: usize, main: SecondaryMain) -> Result<()> {
+    install(stacks, stack_size, main)?;
+
+    let entry = entry_point().ok_or(FfaError::NotSupported)?;
+    Ffa::new().secondary_ep_register(entry)
+}
+
+#[cfg(target_arch = "aarch64")]
+extern "C" fn secondary_main(vcpu_id: u64) -> ! {
+    let main = MAIN.load(Ordering::Acquire);
+
+    if main != 0 {
+        // SAFETY: MAIN is only ever set from a SecondaryMain in install()
+        let main: SecondaryMain = unsafe { core::mem::transmute(main) };
+        main(ExecutionContext::new(vcpu_id as u16));
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+core::arch::global_asm!(
+    ".global ffa_secondary_entry",
+    ".balign 4",
+    "ffa_secondary_entry:",
+    // x0 holds the vCPU id, park contexts without a stack
+    "adrp x1, {count}",
+    "ldr x1, [x1, :lo12:{count}]",
+    "cmp x0, x1",
+    "b.hs 2f",
+    // sp = base + (vcpu_id + 1) * size
+    "adrp x1, {base}",
+    "ldr x1, [x1, :lo12:{base}]",
+    "adrp x2, {size}",
+    "ldr x2, [x2, :lo12:{size}]",
+    "madd x1, x0, x2, x1",
+    "add x1, x1, x2",
+    "mov sp, x1",
+    "b {main}",
+    "2:",
+    "wfe",
+    "b 2b",
+    count = sym STACK_COUNT,
+    base = sym STACK_BASE,
+    size = sym STACK_SIZE,
+    main = sym secondary_main,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Serialises the tests installing stacks, which are global
+    fn install_lock() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[repr(align(16))]
+    struct Stacks([u8; 200]);
+
+    fn stacks() -> &'static mut [u8] {
+        &mut Box::leak(Box::new(Stacks([0; 200]))).0
+    }
+
+    fn park(_: ExecutionContext) -> ! {
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test]
+    fn install_checks_the_stacks() {
+        let _lock = install_lock();
+
+        assert_eq!(install(stacks(), 0, park), Err(FfaError::InvalidParameters));
+        assert_eq!(
+            install(stacks(), 24, park),
+            Err(FfaError::InvalidParameters)
+        );
+        assert_eq!(
+            install(&mut stacks()[1..], 32, park),
+            Err(FfaError::InvalidParameters)
+        );
+
+        assert_eq!(install(stacks(), 64, park), Ok(()));
+        // The 8 bytes left over don't make a stack
+        assert_eq!(STACK_COUNT.load(Ordering::Relaxed), 3);
+        assert_eq!(STACK_SIZE.load(Ordering::Relaxed), 64);
+    }
+
+    #[test]
+    fn vcpu_is_told_from_the_stack() {
+        let _lock = install_lock();
+        let stacks = stacks();
+        let base = stacks.as_ptr() as usize;
+        install(stacks, 64, park).unwrap();
+
+        assert_eq!(vcpu_on_stack(base), 0);
+        assert_eq!(vcpu_on_stack(base + 63), 0);
+        assert_eq!(vcpu_on_stack(base + 64), 1);
+        assert_eq!(vcpu_on_stack(base + 191), 2);
+
+        // Outside the stacks, including the bytes left over
+        assert_eq!(vcpu_on_stack(base + 192), 0);
+        assert_eq!(vcpu_on_stack(base - 1), 0);
+        assert_eq!(vcpu_on_stack(usize::MAX), 0);
+
+        // Test threads don't run on the installed stacks
+        assert_eq!(current_vcpu_id(), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use uuid::Uuid;
+
+use super::msg::{FfaMsg, MsgEvent};
+use super::{Ffa, FfaError, FfaFunctionId, Result};
+
+/// A service reachable through FFA_MSG_SEND_DIRECT_REQ2 at a given UUID.
+pub trait FfaService {
+    fn uuid(&self) -> Uuid;
+
+    /// Handles a request and returns the response payload.
+    ///
+    /// Only `args64` of the returned message is used; the registry fills in
+    /// the function id and endpoint ids.
+    fn handle(&mut self, msg: &FfaMsg) -> Result<FfaMsg>;
+}
+
+/// A service backed by a plain function.
+pub struct StaticService {
+    uuid: Uuid,
+    handler: fn(&FfaMsg) -> Result<FfaMsg>,
+}
+
+impl StaticService {
+    pub const fn new(uuid: Uuid, handler: fn(&FfaMsg) -> Result<FfaMsg>) -> Self {
+        Self { uuid, handler }
+    }
+}
+
+impl FfaService for StaticService {
+    fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    fn handle(&mut self, msg: &FfaMsg) -> Result<FfaMsg> {
+        (self.handler)(msg)
+    }
+}
+
+/// Routes direct requests to registered services by UUID.
+///
+/// When a service fails, or no service matches the request UUID, the
+/// response carries the FF-A error code as described in
+/// [`FfaMsg::response_to`].
+pub struct ServiceRegistry<'a, const N: usize> {
+    services: [Option<&'a mut dyn FfaService>; N],
+}
+
+impl<'a, const N: usize> Default for ServiceRegistry<'a, N> {
+    fn default() -> Self {
+        Self {
+            services: core::array::from_fn(|_| None),
+        }
+    }
+}
+
+impl<'a, const N: usize> ServiceRegistry<'a, N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, service: &'a mut dyn FfaService) -> Result<()> {
+        let uuid = service.uuid();
+
+        if self.services.iter().flatten().any(|s| s.uuid() == uuid) {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let slot = self
+            .services
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(FfaError::NoMemory)?;
+        *slot = Some(service);
+
+        Ok(())
+    }
+
+    /// Routes a request to the service registered for its UUID.
+    pub fn handle(&mut self, msg: &FfaMsg) -> Result<FfaMsg> {
+        if msg.function_id != FfaFunctionId::FfaMsgSendDirectReq2.into() {
+            return Err(FfaError::NotSupported);
+        }
+
+        self.services
+            .iter_mut()
+            .flatten()
+            .find(|s| s.uuid() == msg.uuid)
+            .map_or(Err(FfaError::NotSupported), |s| s.handle(msg))
+    }
+
+    /// Handles a single request and builds the response to send back.
+    pub fn dispatch(&mut self, msg: &FfaMsg) -> FfaMsg {
+        FfaMsg::response_to(msg, self.handle(msg))
+    }
+
+    /// Waits for requests and answers them forever. Only returns on error.
+    ///
+    /// Interrupts returned with FFA_INTERRUPT while waiting are expected to
+    /// have been handled by the partition's exception vectors, the loop just
+    /// goes back to waiting. Partitions taking them from FFA_INTERRUPT
+    /// instead call [`Ffa::msg_wait`] and [`Ffa::msg_resp`] themselves.
+    pub fn run(&mut self, ffa: &Ffa) -> Result<()> {
+        let mut next = ffa.msg_wait()?;
+
+        loop {
+            let msg = match next {
+                MsgEvent::Message(msg) => msg,
+                MsgEvent::Interrupt(_) => {
+                    next = ffa.msg_wait()?;
+                    continue;
+                }
+            };
+
+            let resp = self.dispatch(&msg);
+            next = ffa.msg_resp(&resp)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FfaParams;
+
+    const ECHO: Uuid = Uuid::from_u128(0x1111_2222_3333_4444_5555_6666_7777_8888);
+    const SUM: Uuid = Uuid::from_u128(0x9999_aaaa_bbbb_cccc_dddd_eeee_ffff_0000);
+
+    fn echo(msg: &FfaMsg) -> Result<FfaMsg> {
+        Ok(msg.clone())
+    }
+
+    fn sum(msg: &FfaMsg) -> Result<FfaMsg> {
+        let mut resp = FfaMsg::new();
+        resp.args64[0] = msg.args64.iter().sum();
+        Ok(resp)
+    }
+
+    fn fail(_msg: &FfaMsg) -> Result<FfaMsg> {
+        Err(FfaError::Busy)
+    }
+
+    fn request(uuid: Uuid) -> FfaMsg {
+        FfaMsg::from(FfaParams {
+            x0: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+            x1: 0x8003_8001,
+            x2: uuid.as_u64_pair().0.to_be(),
+            x3: uuid.as_u64_pair().1.to_be(),
+            x4: 1,
+            x5: 2,
+            x17: 3,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn routes_by_uuid() {
+        let mut echo = StaticService::new(ECHO, echo);
+        let mut sum = StaticService::new(SUM, sum);
+        let mut registry = ServiceRegistry::<2>::new();
+        registry.register(&mut echo).unwrap();
+        registry.register(&mut sum).unwrap();
+
+        let resp = registry.dispatch(&request(SUM));
+        assert_eq!(resp.args64[0], 6);
+        assert_eq!(resp.args64[1..], [0; 13]);
+
+        let resp = registry.dispatch(&request(ECHO));
+        assert_eq!(resp.args64, request(ECHO).args64);
+    }
+
+    #[test]
+    fn rejects_duplicates_and_overflow() {
+        let mut a = StaticService::new(ECHO, echo);
+        let mut b = StaticService::new(ECHO, sum);
+        let mut c = StaticService::new(SUM, sum);
+        let mut registry = ServiceRegistry::<1>::new();
+
+        registry.register(&mut a).unwrap();
+        assert_eq!(registry.register(&mut b), Err(FfaError::InvalidParameters));
+        assert_eq!(registry.register(&mut c), Err(FfaError::NoMemory));
+    }
+
+    #[test]
+    fn unknown_uuid_is_not_supported() {
+        let mut echo = StaticService::new(ECHO, echo);
+        let mut registry = ServiceRegistry::<1>::new();
+        registry.register(&mut echo).unwrap();
+
+        assert_eq!(registry.handle(&request(SUM)), Err(FfaError::NotSupported));
+
+        let resp = registry.dispatch(&request(SUM));
+        assert_eq!(resp.args64[0], i64::from(FfaError::NotSupported) as u64);
+        assert_eq!(resp.args64[1..], [0; 13]);
+    }
+
+    #[test]
+    fn only_direct_req2_is_routed() {
+        let mut echo = StaticService::new(ECHO, echo);
+        let mut registry = ServiceRegistry::<1>::new();
+        registry.register(&mut echo).unwrap();
+
+        let mut req = request(ECHO);
+        req.function_id = FfaFunctionId::FfaMsgSendDirectReq.into();
+        assert_eq!(registry.handle(&req), Err(FfaError::NotSupported));
+    }
+
+    #[test]
+    fn response_registers() {
+        let mut echo = StaticService::new(ECHO, echo);
+        let mut fail = StaticService::new(SUM, fail);
+        let mut registry = ServiceRegistry::<2>::new();
+        registry.register(&mut echo).unwrap();
+        registry.register(&mut fail).unwrap();
+
+        let resp = FfaParams::from(&registry.dispatch(&request(ECHO)));
+        assert_eq!(resp.x0, FfaFunctionId::FfaMsgSendDirectResp2.into());
+        assert_eq!(resp.x1, 0x8001_8003);
+        // Reserved in the response
+        assert_eq!((resp.x2, resp.x3), (0, 0));
+        assert_eq!((resp.x4, resp.x5, resp.x17), (1, 2, 3));
+
+        let resp = FfaParams::from(&registry.dispatch(&request(SUM)));
+        assert_eq!(resp.x0, FfaFunctionId::FfaMsgSendDirectResp2.into());
+        assert_eq!((resp.x2, resp.x3), (0, 0));
+        assert_eq!(resp.x4, i64::from(FfaError::Busy) as u64);
+        assert_eq!(resp.x5, 0);
+    }
+}
@@ -0,0 +1,543 @@
+//! Chunked transfer of large payloads over direct messages.
+//!
+//! A transfer is a sequence of direct requests, each carrying up to
+//! [`CHUNK_SIZE`] bytes of payload, answered by the receiver with an
+//! acknowledgement. The first frame announces the total length and the
+//! CRC-32 of the payload, which the receiver checks once the last chunk has
+//! arrived.
+//!
+//! Frame layout in x4-x17:
+//!
+//! ```text
+//! x4      bits[7:0] frame kind, bits[31:16] sequence number,
+//!         bits[47:32] chunk length
+//! x5      START: bits[31:0] total length, bits[63:32] CRC-32
+//!         DATA: offset of the chunk
+//!         NAK: next expected sequence number
+//!         ABORT: FF-A status code
+//! x6-x17  chunk data
+//! ```
+
+use super::msg::FfaMsg;
+use super::{FfaError, FfaFunctionId, Result};
+
+/// Payload bytes carried by a single frame.
+pub const CHUNK_SIZE: usize = 96;
+
+/// Largest payload that can be transferred, bounded by the sequence number.
+pub const MAX_TRANSFER_SIZE: usize = CHUNK_SIZE * u16::MAX as usize;
+
+/// Consecutive retransmissions tolerated before a transfer is abandoned.
+const MAX_RETRIES: u32 = 8;
+
+const FRAME_START: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_ACK: u8 = 3;
+const FRAME_NAK: u8 = 4;
+const FRAME_DONE: u8 = 5;
+const FRAME_ABORT: u8 = 6;
+
+const DATA_REG: usize = 2;
+
+struct Header {
+    kind: u8,
+    seq: u16,
+    len: usize,
+}
+
+impl Header {
+    fn read(msg: &FfaMsg) -> Self {
+        let raw = msg.args64[0];
+        Self {
+            kind: raw as u8,
+            seq: (raw >> 16) as u16,
+            len: ((raw >> 32) as u16) as usize,
+        }
+    }
+
+    fn write(&self, msg: &mut FfaMsg) {
+        msg.args64[0] = self.kind as u64 | (self.seq as u64) << 16 | (self.len as u64) << 32;
+    }
+}
+
+fn frame(kind: u8, seq: u16, arg: u64) -> FfaMsg {
+    let mut msg = FfaMsg::new();
+    Header { kind, seq, len: 0 }.write(&mut msg);
+    msg.args64[1] = arg;
+    msg
+}
+
+/// CRC-32 (IEEE 802.3) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    /// More frames remain, call [`StreamSender::next_frame`] again.
+    InProgress,
+    /// The receiver has acknowledged the whole payload.
+    Complete,
+}
+
+/// Sending side of a transfer.
+pub struct StreamSender<'a> {
+    data: &'a [u8],
+    crc: u32,
+    seq: u16,
+    retries: u32,
+    complete: bool,
+}
+
+impl<'a> StreamSender<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        if data.len() > MAX_TRANSFER_SIZE {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        Ok(Self {
+            data,
+            crc: crc32(data),
+            seq: 0,
+            retries: 0,
+            complete: false,
+        })
+    }
+
+    fn chunk(&self) -> &'a [u8] {
+        let start = (self.seq as usize * CHUNK_SIZE).min(self.data.len());
+        let end = (start + CHUNK_SIZE).min(self.data.len());
+        &self.data[start..end]
+    }
+
+    fn is_last(&self) -> bool {
+        (self.seq as usize + 1) * CHUNK_SIZE >= self.data.len()
+    }
+
+    /// Fills the payload of `msg` with the current frame.
+    ///
+    /// Only `args64` is written, the caller addresses the request. Calling
+    /// this again before handling a response yields the same frame.
+    ///
+    /// Fails with `InvalidParameters` unless `msg` is an
+    /// FFA_MSG_SEND_DIRECT_REQ2, the only request with room for a frame.
+    pub fn next_frame(&self, msg: &mut FfaMsg) -> Result<()> {
+        if msg.function_id != FfaFunctionId::FfaMsgSendDirectReq2.into() {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let chunk = self.chunk();
+
+        msg.args64 = [0; 14];
+        if self.seq == 0 {
+            Header {
+                kind: FRAME_START,
+                seq: 0,
+                len: chunk.len(),
+            }
+            .write(msg);
+            msg.args64[1] = self.data.len() as u64 | (self.crc as u64) << 32;
+        } else {
+            Header {
+                kind: FRAME_DATA,
+                seq: self.seq,
+                len: chunk.len(),
+            }
+            .write(msg);
+            msg.args64[1] = (self.seq as usize * CHUNK_SIZE) as u64;
+        }
+
+        for (reg, bytes) in msg.args64[DATA_REG..].iter_mut().zip(chunk.chunks(8)) {
+            let mut buf = [0u8; 8];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            *reg = u64::from_le_bytes(buf);
+        }
+
+        Ok(())
+    }
+
+    /// Advances the transfer according to the receiver's response.
+    pub fn handle_response(&mut self, resp: &FfaMsg) -> Result<SendStatus> {
+        if self.complete {
+            return Ok(SendStatus::Complete);
+        }
+
+        let header = Header::read(resp);
+
+        match header.kind {
+            FRAME_ACK if header.seq == self.seq && !self.is_last() => {
+                self.seq += 1;
+                self.retries = 0;
+                Ok(SendStatus::InProgress)
+            }
+            FRAME_DONE if header.seq == self.seq && self.is_last() => {
+                self.complete = true;
+                Ok(SendStatus::Complete)
+            }
+            FRAME_NAK if resp.args64[1] <= self.seq as u64 && self.retries < MAX_RETRIES => {
+                self.seq = resp.args64[1] as u16;
+                self.retries += 1;
+                Ok(SendStatus::InProgress)
+            }
+            FRAME_ABORT => Err((resp.args64[1] as i64).into()),
+            _ => Err(FfaError::Aborted),
+        }
+    }
+
+    /// Drives the whole transfer, using `send` to deliver each request built
+    /// from `template` and return its response.
+    ///
+    /// Fails with `InvalidParameters`, before anything is sent, unless
+    /// `template` is an FFA_MSG_SEND_DIRECT_REQ2.
+    pub fn transfer<F>(&mut self, template: &FfaMsg, mut send: F) -> Result<()>
+    where
+        F: FnMut(&FfaMsg) -> Result<FfaMsg>,
+    {
+        let mut req = template.clone();
+
+        loop {
+            self.next_frame(&mut req)?;
+            let resp = send(&req)?;
+
+            if self.handle_response(&resp)? == SendStatus::Complete {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvStatus {
+    /// Waiting for a transfer to start.
+    Idle,
+    InProgress,
+    /// The payload has been received and its CRC verified.
+    Complete(usize),
+    Failed(FfaError),
+}
+
+/// Receiving side of a transfer, reassembling into a caller buffer.
+pub struct StreamReceiver<'a> {
+    buf: &'a mut [u8],
+    total: usize,
+    crc: u32,
+    next_seq: u16,
+    status: RecvStatus,
+}
+
+impl<'a> StreamReceiver<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            total: 0,
+            crc: 0,
+            next_seq: 0,
+            status: RecvStatus::Idle,
+        }
+    }
+
+    pub fn status(&self) -> &RecvStatus {
+        &self.status
+    }
+
+    /// The received payload, once the transfer is complete.
+    pub fn payload(&self) -> Option<&[u8]> {
+        match self.status {
+            RecvStatus::Complete(len) => Some(&self.buf[..len]),
+            _ => None,
+        }
+    }
+
+    /// Prepares for a new transfer into the same buffer.
+    pub fn reset(&mut self) {
+        self.next_seq = 0;
+        self.status = RecvStatus::Idle;
+    }
+
+    /// Consumes a request frame and returns the response payload to send
+    /// back, see [`FfaMsg::response_to`].
+    pub fn receive(&mut self, req: &FfaMsg) -> FfaMsg {
+        let header = Header::read(req);
+
+        match header.kind {
+            FRAME_START => {
+                let total = (req.args64[1] as u32) as usize;
+
+                if total > self.buf.len() {
+                    return self.abort(FfaError::NoMemory);
+                }
+
+                self.total = total;
+                self.crc = (req.args64[1] >> 32) as u32;
+                self.next_seq = 0;
+                self.status = RecvStatus::InProgress;
+                self.accept(req, &header)
+            }
+            FRAME_DATA => match self.status {
+                RecvStatus::InProgress if header.seq == self.next_seq => self.accept(req, &header),
+                // A retransmission of a frame we already took, repeat the answer
+                RecvStatus::InProgress if header.seq < self.next_seq => {
+                    frame(FRAME_ACK, header.seq, 0)
+                }
+                RecvStatus::Complete(_) if header.seq.wrapping_add(1) == self.next_seq => {
+                    frame(FRAME_DONE, header.seq, 0)
+                }
+                RecvStatus::InProgress => frame(FRAME_NAK, header.seq, self.next_seq as u64),
+                _ => frame(FRAME_ABORT, header.seq, i64::from(FfaError::Denied) as u64),
+            },
+            _ => self.abort(FfaError::InvalidParameters),
+        }
+    }
+
+    fn accept(&mut self, req: &FfaMsg, header: &Header) -> FfaMsg {
+        let offset = header.seq as usize * CHUNK_SIZE;
+        let expected = self.total.saturating_sub(offset).min(CHUNK_SIZE);
+
+        if header.len != expected || (header.seq != 0 && req.args64[1] != offset as u64) {
+            return self.abort(FfaError::InvalidParameters);
+        }
+
+        let chunk = &mut self.buf[offset..offset + expected];
+        for (bytes, reg) in chunk.chunks_mut(8).zip(req.args64[DATA_REG..].iter()) {
+            bytes.copy_from_slice(&reg.to_le_bytes()[..bytes.len()]);
+        }
+
+        self.next_seq = header.seq.wrapping_add(1);
+
+        if offset + expected < self.total {
+            return frame(FRAME_ACK, header.seq, 0);
+        }
+
+        if crc32(&self.buf[..self.total]) != self.crc {
+            return self.abort(FfaError::Aborted);
+        }
+
+        self.status = RecvStatus::Complete(self.total);
+        frame(FRAME_DONE, header.seq, 0)
+    }
+
+    fn abort(&mut self, err: FfaError) -> FfaMsg {
+        let code = i64::from(err);
+        self.status = RecvStatus::Failed(err);
+        frame(FRAME_ABORT, self.next_seq, code as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::FakeConduit;
+    use crate::{Conduit, FfaFunctionId, FfaParams};
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    fn template() -> FfaMsg {
+        FfaMsg {
+            function_id: FfaFunctionId::FfaMsgSendDirectReq2.into(),
+            source_id: 0x8001,
+            destination_id: 0x8002,
+            ..Default::default()
+        }
+    }
+
+    /// Delivers `req` to `rx` through the registers of a fake conduit, as a
+    /// direct request and its response would travel.
+    fn deliver(conduit: &mut FakeConduit<32>, rx: &mut StreamReceiver, req: &FfaMsg) -> FfaMsg {
+        conduit.call(req.into());
+        let req = FfaMsg::from(*conduit.calls().last().unwrap());
+
+        let resp = FfaMsg::response_to(&req, Ok(rx.receive(&req)));
+        FfaMsg::from(FfaParams::from(&resp))
+    }
+
+    #[test]
+    fn crc32_known_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 5 * CHUNK_SIZE - 3] {
+            let data = payload(len);
+            let mut buf = vec![0u8; 1024];
+            let mut rx = StreamReceiver::new(&mut buf);
+            let mut conduit = FakeConduit::<32>::new();
+
+            let mut tx = StreamSender::new(&data).unwrap();
+            tx.transfer(&template(), |req| Ok(deliver(&mut conduit, &mut rx, req)))
+                .unwrap();
+
+            assert_eq!(rx.status(), &RecvStatus::Complete(len));
+            assert_eq!(rx.payload(), Some(&data[..]));
+            assert_eq!(conduit.calls().len(), len.div_ceil(CHUNK_SIZE).max(1));
+
+            let first = conduit.calls()[0];
+            assert_eq!(first.x0, FfaFunctionId::FfaMsgSendDirectReq2.into());
+            assert_eq!(first.x1, 0x8001_8002);
+        }
+    }
+
+    #[test]
+    fn rejects_templates_other_than_direct_req2() {
+        let data = payload(10);
+        let mut tx = StreamSender::new(&data).unwrap();
+
+        for id in [
+            FfaFunctionId::FfaMsgSendDirectReq,
+            FfaFunctionId::FfaMsgSendDirectResp2,
+        ] {
+            let template = FfaMsg {
+                function_id: id.into(),
+                ..template()
+            };
+            let mut sent = 0;
+
+            assert_eq!(
+                tx.transfer(&template, |req| {
+                    sent += 1;
+                    Ok(req.clone())
+                }),
+                Err(FfaError::InvalidParameters)
+            );
+            assert_eq!(sent, 0);
+        }
+    }
+
+    #[test]
+    fn nak_rewinds_the_sender() {
+        let data = payload(4 * CHUNK_SIZE);
+        let mut buf = [0u8; 4 * CHUNK_SIZE];
+        let mut rx = StreamReceiver::new(&mut buf);
+        let mut tx = StreamSender::new(&data).unwrap();
+        let mut req = template();
+
+        // START and chunk 1 arrive
+        for _ in 0..2 {
+            tx.next_frame(&mut req).unwrap();
+            let resp = rx.receive(&req);
+            assert_eq!(tx.handle_response(&resp), Ok(SendStatus::InProgress));
+        }
+
+        // Chunk 2 is lost, chunk 3 is refused
+        tx.next_frame(&mut req).unwrap();
+        tx.handle_response(&frame(FRAME_ACK, 2, 0)).unwrap();
+        tx.next_frame(&mut req).unwrap();
+        let resp = rx.receive(&req);
+        assert_eq!(Header::read(&resp).kind, FRAME_NAK);
+        assert_eq!(resp.args64[1], 2);
+
+        // Back to chunk 2
+        assert_eq!(tx.handle_response(&resp), Ok(SendStatus::InProgress));
+        let mut status = SendStatus::InProgress;
+        while status == SendStatus::InProgress {
+            tx.next_frame(&mut req).unwrap();
+            status = tx.handle_response(&rx.receive(&req)).unwrap();
+        }
+
+        assert_eq!(rx.payload(), Some(&data[..]));
+    }
+
+    #[test]
+    fn gives_up_after_too_many_naks() {
+        let data = payload(2 * CHUNK_SIZE);
+        let mut tx = StreamSender::new(&data).unwrap();
+
+        for _ in 0..MAX_RETRIES {
+            assert_eq!(
+                tx.handle_response(&frame(FRAME_NAK, 0, 0)),
+                Ok(SendStatus::InProgress)
+            );
+        }
+        assert_eq!(
+            tx.handle_response(&frame(FRAME_NAK, 0, 0)),
+            Err(FfaError::Aborted)
+        );
+    }
+
+    #[test]
+    fn receiver_aborts_oversize_transfers() {
+        let data = payload(3 * CHUNK_SIZE);
+        let mut buf = [0u8; CHUNK_SIZE];
+        let mut rx = StreamReceiver::new(&mut buf);
+        let mut tx = StreamSender::new(&data).unwrap();
+
+        let result = tx.transfer(&template(), |req| Ok(rx.receive(req)));
+
+        assert_eq!(result, Err(FfaError::NoMemory));
+        assert_eq!(rx.status(), &RecvStatus::Failed(FfaError::NoMemory));
+        assert_eq!(rx.payload(), None);
+    }
+
+    #[test]
+    fn receiver_aborts_on_crc_mismatch() {
+        let data = payload(2 * CHUNK_SIZE);
+        let mut buf = [0u8; 2 * CHUNK_SIZE];
+        let mut rx = StreamReceiver::new(&mut buf);
+        let mut tx = StreamSender::new(&data).unwrap();
+
+        let result = tx.transfer(&template(), |req| {
+            let mut req = req.clone();
+            req.args64[DATA_REG] ^= 1;
+            Ok(rx.receive(&req))
+        });
+
+        assert_eq!(result, Err(FfaError::Aborted));
+        assert_eq!(rx.status(), &RecvStatus::Failed(FfaError::Aborted));
+    }
+
+    #[test]
+    fn last_sequence_number_does_not_overflow() {
+        let data = payload(8);
+        let mut buf = [0u8; 8];
+        let mut rx = StreamReceiver::new(&mut buf);
+        let mut tx = StreamSender::new(&data).unwrap();
+        tx.transfer(&template(), |req| Ok(rx.receive(req))).unwrap();
+
+        let mut req = template();
+        Header {
+            kind: FRAME_DATA,
+            seq: u16::MAX,
+            len: 0,
+        }
+        .write(&mut req);
+
+        let resp = rx.receive(&req);
+        assert_eq!(Header::read(&resp).kind, FRAME_ABORT);
+    }
+
+    #[test]
+    fn rejects_unknown_frames() {
+        let mut buf = [0u8; 8];
+        let mut rx = StreamReceiver::new(&mut buf);
+
+        let resp = rx.receive(&frame(FRAME_ACK, 0, 0));
+        assert_eq!(Header::read(&resp).kind, FRAME_ABORT);
+        assert_eq!(
+            resp.args64[1],
+            i64::from(FfaError::InvalidParameters) as u64
+        );
+
+        rx.reset();
+        assert_eq!(rx.status(), &RecvStatus::Idle);
+    }
+}
@@ -0,0 +1,124 @@
+//! Monotonic time from the Arm generic timer.
+//!
+//! [`Instant`] reads the virtual counter, CNTVCT_EL0, which runs at the
+//! frequency given by CNTFRQ_EL0. On other architectures, e.g. when running
+//! host unit tests, the counter stands still at zero unless a test moves it
+//! forward.
+
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Point in time, in counter ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(counter())
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since `earlier`, zero if `earlier` is later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Self)
+    }
+}
+
+/// Adds `rhs`, saturating at the end of time.
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Self(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.saturating_duration_since(rhs)
+    }
+}
+
+/// Counter frequency in Hz.
+pub fn frequency() -> u64 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let freq: u64;
+        // SAFETY: reading CNTFRQ_EL0 has no side effects
+        unsafe {
+            core::arch::asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nomem, nostack));
+        }
+        freq.max(1)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        NANOS_PER_SEC as u64
+    }
+}
+
+fn counter() -> u64 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let ticks: u64;
+        // SAFETY: reading CNTVCT_EL0 has no side effects. The ISB keeps the
+        // read from being hoisted above earlier instructions.
+        unsafe {
+            core::arch::asm!("isb", "mrs {}, cntvct_el0", out(reg) ticks, options(nomem, nostack));
+        }
+        ticks
+    }
+
+    #[cfg(all(not(target_arch = "aarch64"), test))]
+    {
+        FAKE_TICKS.with(|ticks| ticks.get())
+    }
+
+    #[cfg(all(not(target_arch = "aarch64"), not(test)))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+std::thread_local! {
+    static FAKE_TICKS: core::cell::Cell<u64> = const { core::cell::Cell::new(0) };
+}
+
+/// Moves the host counter of the calling test thread forward by `ticks`.
+#[cfg(test)]
+pub(crate) fn advance(ticks: u64) {
+    FAKE_TICKS.with(|t| t.set(t.get() + ticks));
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * frequency() as u128 / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
@@ -0,0 +1,463 @@
+//! Cooperative timers on top of FFA_YIELD.
+//!
+//! Partitions have no timer of their own to wait on, but can yield the CPU
+//! asking to be resumed after a timeout. A [`TimerQueue`] keeps the pending
+//! timers in a deadline heap, yields until the nearest one and fires the
+//! expired ones once the partition runs again. Being resumed early is
+//! harmless: timers only fire once their deadline has passed.
+//!
+//! With the partition runtime, the queue plugs into
+//! [`PartitionHandler::idle`](crate::runtime::PartitionHandler::idle) with
+//! [`TimerQueue::timeout`] and
+//! [`PartitionHandler::resumed`](crate::runtime::PartitionHandler::resumed)
+//! with [`TimerQueue::poll`].
+
+use core::time::Duration;
+
+use super::interrupt::FfaInterrupt;
+use super::time::Instant;
+use super::yld::{FfaYield, YieldResult};
+use super::{Conduit, FfaError, Result, SmcConduit};
+
+/// Called with the id of the timer that expired.
+pub type TimerCallback = fn(TimerId);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u32);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: TimerCallback,
+    id: TimerId,
+}
+
+impl Timer {
+    /// Heap order: earliest deadline first, then oldest timer first.
+    fn before(&self, other: &Timer) -> bool {
+        (self.deadline, self.id.0) < (other.deadline, other.id.0)
+    }
+}
+
+/// Fixed capacity set of pending timers.
+pub struct TimerQueue<const N: usize> {
+    heap: [Option<Timer>; N],
+    len: usize,
+    next_id: u32,
+}
+
+impl<const N: usize> Default for TimerQueue<N> {
+    fn default() -> Self {
+        Self {
+            heap: [None; N],
+            len: 0,
+            next_id: 0,
+        }
+    }
+}
+
+impl<const N: usize> TimerQueue<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `callback` once `deadline` has passed.
+    ///
+    /// Fails with `NoMemory` if the queue is full.
+    pub fn schedule_at(&mut self, deadline: Instant, callback: TimerCallback) -> Result<TimerId> {
+        self.insert(deadline, None, callback)
+    }
+
+    /// Calls `callback` once `delay` has elapsed.
+    pub fn schedule_after(&mut self, delay: Duration, callback: TimerCallback) -> Result<TimerId> {
+        self.insert(Instant::now() + delay, None, callback)
+    }
+
+    /// Calls `callback` every `period`, until cancelled.
+    ///
+    /// Periods missed while the partition wasn't running are skipped, the
+    /// callback fires once for them.
+    pub fn schedule_periodic(
+        &mut self,
+        period: Duration,
+        callback: TimerCallback,
+    ) -> Result<TimerId> {
+        if period.is_zero() {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        self.insert(Instant::now() + period, Some(period), callback)
+    }
+
+    /// Removes timer `id`, returning whether it was pending.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.position(id) {
+            Some(index) => {
+                self.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Deadline of the timer expiring first.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.get(0).map(|t| t.deadline)
+    }
+
+    /// How long the partition can wait from `now` before the next timer
+    /// expires, `None` without pending timers.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    /// Fires the timers whose deadline is at or before `now`, returning how
+    /// many fired.
+    pub fn poll(&mut self, now: Instant) -> usize {
+        let mut fired = 0;
+
+        while let Some(mut timer) = self.get(0).filter(|t| t.deadline <= now) {
+            match timer.period {
+                Some(period) => {
+                    timer.deadline = timer.deadline + period;
+                    if timer.deadline <= now {
+                        timer.deadline = now + period;
+                    }
+                    self.heap[0] = Some(timer);
+                    self.sift_down(0);
+                }
+                None => self.remove(0),
+            }
+
+            (timer.callback)(timer.id);
+            fired += 1;
+        }
+
+        fired
+    }
+
+    /// Yields through `conduit` until the next timer expires, then fires the
+    /// expired timers.
+    ///
+    /// Fails with `NoData` without pending timers. Returns the interrupt the
+    /// partition was resumed with FFA_INTERRUPT to handle, if any.
+    pub fn run_once<C: Conduit>(&mut self, conduit: &mut C) -> Result<Option<FfaInterrupt>> {
+        let timeout = self.timeout(Instant::now()).ok_or(FfaError::NoData)?;
+
+        let interrupt = match timeout.is_zero() {
+            true => None,
+            false => yield_for(conduit, timeout)?,
+        };
+        self.poll(Instant::now());
+
+        Ok(interrupt)
+    }
+
+    /// Yields through `conduit` until `deadline`, firing the timers expiring
+    /// in the meantime.
+    ///
+    /// Returns early with the interrupt the partition was resumed with
+    /// FFA_INTERRUPT to handle, if any, after firing the expired timers.
+    pub fn sleep_until<C: Conduit>(
+        &mut self,
+        conduit: &mut C,
+        deadline: Instant,
+    ) -> Result<Option<FfaInterrupt>> {
+        loop {
+            let now = Instant::now();
+            self.poll(now);
+
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let timeout = match self.next_deadline() {
+                Some(next) if next < deadline => next - now,
+                _ => deadline - now,
+            };
+            if let Some(interrupt) = yield_for(conduit, timeout)? {
+                self.poll(Instant::now());
+                return Ok(Some(interrupt));
+            }
+        }
+    }
+
+    fn insert(
+        &mut self,
+        deadline: Instant,
+        period: Option<Duration>,
+        callback: TimerCallback,
+    ) -> Result<TimerId> {
+        let slot = self.heap.get_mut(self.len).ok_or(FfaError::NoMemory)?;
+        let id = TimerId(self.next_id);
+
+        *slot = Some(Timer {
+            deadline,
+            period,
+            callback,
+            id,
+        });
+        self.next_id = self.next_id.wrapping_add(1);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+
+        Ok(id)
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.len -= 1;
+        self.heap.swap(index, self.len);
+        self.heap[self.len] = None;
+
+        if index < self.len {
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+    }
+
+    fn position(&self, id: TimerId) -> Option<usize> {
+        (0..self.len).find(|&i| self.get(i).is_some_and(|t| t.id == id))
+    }
+
+    fn get(&self, index: usize) -> Option<Timer> {
+        self.heap[..self.len].get(index).copied().flatten()
+    }
+
+    fn before(&self, a: usize, b: usize) -> bool {
+        match (self.get(a), self.get(b)) {
+            (Some(a), Some(b)) => a.before(&b),
+            _ => false,
+        }
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if !self.before(index, parent) {
+                break;
+            }
+            self.heap.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut first = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.len && self.before(child, first) {
+                    first = child;
+                }
+            }
+            if first == index {
+                break;
+            }
+            self.heap.swap(index, first);
+            index = first;
+        }
+    }
+}
+
+/// Yields for up to `timeout`, returning the interrupt the partition was
+/// resumed with FFA_INTERRUPT to handle, if any. Being resumed early by
+/// another event isn't an error.
+fn yield_for<C: Conduit>(conduit: &mut C, timeout: Duration) -> Result<Option<FfaInterrupt>> {
+    match FfaYield::with_duration(timeout).exec_with(conduit)? {
+        YieldResult::Interrupted(interrupt) => Ok(Some(interrupt)),
+        YieldResult::Timeout | YieldResult::Event => Ok(None),
+    }
+}
+
+/// Yields the CPU until `deadline` has passed.
+///
+/// Returns early with the interrupt the partition was resumed with
+/// FFA_INTERRUPT to handle, if any.
+pub fn sleep_until(deadline: Instant) -> Result<Option<FfaInterrupt>> {
+    while Instant::now() < deadline {
+        if let Some(interrupt) = yield_for(&mut SmcConduit, deadline - Instant::now())? {
+            return Ok(Some(interrupt));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Yields the CPU until `duration` has elapsed, see [`sleep_until`].
+pub fn sleep(duration: Duration) -> Result<Option<FfaInterrupt>> {
+    sleep_until(Instant::now() + duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ffa_success, FakeConduit};
+    use crate::{time, FfaFunctionId, FfaParams};
+    use std::cell::RefCell;
+
+    std::thread_local! {
+        static FIRED: RefCell<Vec<TimerId>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(id: TimerId) {
+        FIRED.with(|fired| fired.borrow_mut().push(id));
+    }
+
+    fn fired() -> Vec<TimerId> {
+        FIRED.with(|fired| fired.take())
+    }
+
+    // On the host, Instant::now() is 0 and ticks are nanoseconds
+    fn at(ns: u64) -> Instant {
+        Instant::from_ticks(ns)
+    }
+
+    #[test]
+    fn fires_by_deadline_then_insertion_order() {
+        let mut queue = TimerQueue::<8>::new();
+        let c = queue.schedule_at(at(30), record).unwrap();
+        let a = queue.schedule_at(at(10), record).unwrap();
+        let d = queue.schedule_at(at(30), record).unwrap();
+        let b = queue.schedule_at(at(20), record).unwrap();
+        let e = queue.schedule_at(at(30), record).unwrap();
+
+        assert_eq!(queue.next_deadline(), Some(at(10)));
+        assert_eq!(queue.timeout(at(4)), Some(Duration::from_nanos(6)));
+        assert_eq!(queue.timeout(at(15)), Some(Duration::ZERO));
+
+        assert_eq!(queue.poll(at(9)), 0);
+        assert_eq!(queue.poll(at(20)), 2);
+        assert_eq!(fired(), [a, b]);
+        assert_eq!(queue.poll(at(100)), 3);
+        assert_eq!(fired(), [c, d, e]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.timeout(at(100)), None);
+    }
+
+    #[test]
+    fn cancel_from_the_middle() {
+        let mut queue = TimerQueue::<8>::new();
+        let ids: Vec<_> = [50, 10, 40, 20, 60, 30, 70]
+            .into_iter()
+            .map(|ns| queue.schedule_at(at(ns), record).unwrap())
+            .collect();
+
+        // 40 and 20 sit below the root of the heap
+        assert!(queue.cancel(ids[2]));
+        assert!(queue.cancel(ids[3]));
+        assert!(!queue.cancel(ids[3]));
+        assert_eq!(queue.len(), 5);
+
+        assert_eq!(queue.poll(at(100)), 5);
+        assert_eq!(fired(), [ids[1], ids[5], ids[0], ids[4], ids[6]]);
+    }
+
+    #[test]
+    fn periodic_timers_skip_missed_periods() {
+        let mut queue = TimerQueue::<2>::new();
+        assert_eq!(
+            queue.schedule_periodic(Duration::ZERO, record),
+            Err(FfaError::InvalidParameters)
+        );
+        let id = queue
+            .schedule_periodic(Duration::from_nanos(10), record)
+            .unwrap();
+        assert_eq!(queue.next_deadline(), Some(at(10)));
+
+        assert_eq!(queue.poll(at(10)), 1);
+        assert_eq!(queue.next_deadline(), Some(at(20)));
+
+        // Periods at 20, 30 and 40 were missed, it fires once
+        assert_eq!(queue.poll(at(45)), 1);
+        assert_eq!(queue.next_deadline(), Some(at(55)));
+        assert_eq!(fired(), [id, id]);
+
+        assert!(queue.cancel(id));
+        assert_eq!(queue.poll(at(1000)), 0);
+    }
+
+    #[test]
+    fn full_queue_is_no_memory() {
+        let mut queue = TimerQueue::<2>::new();
+        queue.schedule_at(at(1), record).unwrap();
+        let id = queue.schedule_at(at(2), record).unwrap();
+
+        assert_eq!(queue.schedule_at(at(3), record), Err(FfaError::NoMemory));
+        assert_eq!(
+            queue.schedule_after(Duration::from_nanos(3), record),
+            Err(FfaError::NoMemory)
+        );
+
+        assert!(queue.cancel(id));
+        assert!(queue.schedule_at(at(3), record).is_ok());
+    }
+
+    #[test]
+    fn run_once_yields_until_the_next_deadline() {
+        let mut conduit = FakeConduit::<2>::from_responses(&[ffa_success(0)]);
+        let mut queue = TimerQueue::<2>::new();
+        assert_eq!(queue.run_once(&mut conduit), Err(FfaError::NoData));
+
+        queue.schedule_at(at(0x1_0000_0005), record).unwrap();
+        // The host clock doesn't move, so nothing fires
+        assert_eq!(queue.run_once(&mut conduit), Ok(None));
+        assert!(fired().is_empty());
+
+        let call = conduit.calls()[0];
+        assert_eq!(call.x0, FfaFunctionId::FfaMsgYield.into());
+        assert_eq!((call.x2, call.x3), (5, 1));
+    }
+
+    /// Moves the clock forward by the timeout of each yield.
+    struct ClockConduit<const N: usize>(FakeConduit<N>);
+
+    impl<const N: usize> Conduit for ClockConduit<N> {
+        fn call(&mut self, params: FfaParams) -> FfaParams {
+            time::advance(params.x3 << 32 | params.x2);
+            self.0.call(params)
+        }
+    }
+
+    #[test]
+    fn sleep_until_fires_timers_on_the_way() {
+        let mut conduit = ClockConduit(FakeConduit::<4>::from_responses(&[ffa_success(0); 2]));
+        let mut queue = TimerQueue::<2>::new();
+        let id = queue.schedule_at(at(10), record).unwrap();
+
+        assert_eq!(queue.sleep_until(&mut conduit, at(25)), Ok(None));
+        assert_eq!(fired(), [id]);
+        assert_eq!(Instant::now(), at(25));
+
+        let timeouts: Vec<_> = conduit.0.calls().iter().map(|call| call.x2).collect();
+        assert_eq!(timeouts, [10, 15]);
+    }
+
+    #[test]
+    fn sleep_until_returns_interrupts() {
+        let interrupt = FfaParams {
+            x0: FfaFunctionId::FfaInterrupt.into(),
+            x2: 42,
+            ..Default::default()
+        };
+        let mut conduit = ClockConduit(FakeConduit::<4>::from_responses(&[interrupt]));
+        let mut queue = TimerQueue::<2>::new();
+        let id = queue.schedule_at(at(10), record).unwrap();
+
+        assert_eq!(
+            queue.sleep_until(&mut conduit, at(25)),
+            Ok(Some(FfaInterrupt::try_from(interrupt).unwrap()))
+        );
+        // The timer expired while yielding
+        assert_eq!(fired(), [id]);
+        assert_eq!(conduit.0.calls().len(), 1);
+    }
+}
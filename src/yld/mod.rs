@@ -1,10 +1,26 @@
-use super::{ffa_smc, FfaError, FfaFunctionId, FfaParams};
+use core::time::Duration;
+
+use super::interrupt::FfaInterrupt;
+use super::time::Instant;
+use super::{Conduit, FfaError, FfaFunctionId, FfaParams, Result};
+
+/// How a yielding partition came to run again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum YieldResult {
+    /// The requested timeout elapsed.
+    Timeout,
+    /// Resumed before the timeout, e.g. because the scheduler had cycles to
+    /// spare.
+    Event,
+    /// Resumed with FFA_INTERRUPT to handle an interrupt, see
+    /// [`crate::interrupt`].
+    Interrupted(FfaInterrupt),
+}
 
 #[derive(Default)]
 pub struct FfaYield {
     pub function_id: u64,
-    pub vcpu_id: u16,
-    pub endpoint_id: u16,
+    /// Timeout in nanoseconds, 0 for none.
     pub timeout_lo: u32,
     pub timeout_hi: u32,
 }
@@ -13,7 +29,7 @@ impl From<&FfaYield> for FfaParams {
     fn from(msg: &FfaYield) -> Self {
         FfaParams {
             x0: FfaFunctionId::FfaMsgYield.into(),
-            x1: ((msg.endpoint_id as u64) << 16) | (msg.vcpu_id as u64),
+            // w1 is reserved and must be zero
             x2: msg.timeout_lo as u64,
             x3: msg.timeout_hi as u64,
             ..Default::default()
@@ -22,31 +38,105 @@ impl From<&FfaYield> for FfaParams {
 }
 
 impl FfaYield {
+    /// Yield with a timeout of `timeout` nanoseconds, 0 for none.
     pub fn new(timeout: u64) -> Self {
         FfaYield {
             function_id: FfaFunctionId::FfaMsgYield.into(),
-            vcpu_id: 0,
-            endpoint_id: 0,
             timeout_lo: timeout as u32,
             timeout_hi: (timeout >> 32) as u32,
         }
     }
 
+    /// Yield asking to be resumed after `timeout`, saturating at the largest
+    /// timeout the ABI can express.
+    ///
+    /// Timeouts below 1ns are rounded up to it, as a timeout of 0 would mean
+    /// none at all.
+    pub fn with_duration(timeout: Duration) -> Self {
+        Self::new(timeout.as_nanos().clamp(1, u64::MAX as u128) as u64)
+    }
+
+    pub fn timeout(&self) -> u64 {
+        ((self.timeout_hi as u64) << 32) | self.timeout_lo as u64
+    }
+
     pub fn exec(&self) -> FfaError {
-        let params: FfaParams = self.into();
-
-        let result = ffa_smc(params);
-
-        match result.x0.into() {
-            FfaFunctionId::FfaSuccess32 => FfaError::Ok,
-            FfaFunctionId::FfaError => (result.x2 as i64).into(),
-            _ => {
-                // Panic on debug builds else return error to handler on release
-                #[cfg(debug_assertions)]
-                panic!("Unknown return from FfaYield: {}", result.x0);
-                #[cfg(not(debug_assertions))]
-                FfaError::InvalidParameters
+        match self.exec_with(&mut super::SmcConduit) {
+            Ok(YieldResult::Interrupted(_)) => FfaError::Interrupted,
+            Ok(_) => FfaError::Ok,
+            Err(err) => err,
+        }
+    }
+
+    /// Yields through `conduit`, telling whether the timeout elapsed by the
+    /// time the partition runs again, or which interrupt it was resumed to
+    /// handle.
+    pub fn exec_with<C: Conduit>(&self, conduit: &mut C) -> Result<YieldResult> {
+        let start = Instant::now();
+        let result = conduit.call(self.into());
+
+        match FfaFunctionId::try_from(result.x0) {
+            Ok(FfaFunctionId::FfaSuccess32 | FfaFunctionId::FfaSuccess64) => {
+                Ok(self.classify(start, Instant::now()))
+            }
+            Ok(FfaFunctionId::FfaError) => Err((result.x2 as i32 as i64).into()),
+            // Resumed early to handle an interrupt, see crate::interrupt
+            Ok(FfaFunctionId::FfaInterrupt) => {
+                FfaInterrupt::try_from(result).map(YieldResult::Interrupted)
             }
+            _ => Err(FfaError::UnknownError),
         }
     }
+
+    /// Whether a yield started at `start` and resumed at `now` timed out.
+    pub(crate) fn classify(&self, start: Instant, now: Instant) -> YieldResult {
+        let timeout = Duration::from_nanos(self.timeout());
+
+        if !timeout.is_zero() && now.saturating_duration_since(start) >= timeout {
+            YieldResult::Timeout
+        } else {
+            YieldResult::Event
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::FakeConduit;
+
+    #[test]
+    fn with_duration_always_sets_a_timeout() {
+        assert_eq!(FfaYield::with_duration(Duration::ZERO).timeout(), 1);
+        assert_eq!(
+            FfaYield::with_duration(Duration::from_nanos(1)).timeout(),
+            1
+        );
+        assert_eq!(
+            FfaYield::with_duration(Duration::from_secs(5)).timeout(),
+            5_000_000_000
+        );
+        assert_eq!(FfaYield::with_duration(Duration::MAX).timeout(), u64::MAX);
+
+        let params = FfaParams::from(&FfaYield::with_duration(Duration::ZERO));
+        assert_eq!((params.x2, params.x3), (1, 0));
+    }
+
+    #[test]
+    fn interrupts_are_returned() {
+        let mut conduit = FakeConduit::<1>::from_responses(&[FfaParams {
+            x0: FfaFunctionId::FfaInterrupt.into(),
+            x2: 42,
+            ..Default::default()
+        }]);
+
+        assert_eq!(
+            FfaYield::new(0).exec_with(&mut conduit),
+            Ok(YieldResult::Interrupted(FfaInterrupt {
+                endpoint_id: 0,
+                vcpu_id: 0,
+                interrupt_id: 42,
+            }))
+        );
+    }
 }